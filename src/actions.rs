@@ -0,0 +1,532 @@
+use anyhow::{bail, Context, Result};
+use futures_util::future::BoxFuture;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{self, AsyncReadExt};
+use tokio::process::Command;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time;
+use uzers::os::unix::UserExt;
+use crate::config::{ActionConfig, Config, PowerActionConfig};
+use crate::home_assistant::{EntityRegistrationBuilder, HomeAssistant};
+
+/// How much of the standard output of a command is published with its result, in bytes.
+const MAX_STDOUT_LEN: usize = 4096;
+
+/// A command run when a button is pressed in Home Assistant.
+#[derive(Clone, Debug)]
+pub struct ActionCommand {
+    pub program: String,
+    pub args: Vec<String>,
+    /// How long the command may run before it is killed.
+    pub timeout: Duration,
+    /// The user to run the command as, or `None` to run it as the user running the daemon.
+    pub user: Option<String>,
+}
+
+impl ActionCommand {
+    /// Create a command from a list of the program followed by its arguments.
+    fn from_argv(argv: &[String]) -> Result<Self> {
+        let Some((program, args)) = argv.split_first() else {
+            bail!("An action command cannot be empty");
+        };
+
+        Ok(Self {
+            program: program.clone(),
+            args: args.to_vec(),
+            timeout: Duration::from_secs(60),
+            user: None,
+        })
+    }
+}
+
+/// The outcome of running an action command.
+#[derive(Debug)]
+pub struct ActionOutput {
+    /// The exit code of the command, if it exited normally.
+    pub exit_code: Option<i32>,
+    /// The standard output of the command, truncated to [`MAX_STDOUT_LEN`] bytes.
+    pub stdout: String,
+    /// Whether the command was killed for running longer than its timeout.
+    pub timed_out: bool,
+}
+
+/// The result of an action, published to its `system-mqtt/<id>/<entity>/result` topic.
+#[derive(Serialize, Debug)]
+pub struct ActionResult {
+    #[serde(skip)]
+    pub entity_id: String,
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub timed_out: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Runs the commands behind button entities.
+pub trait ActionRunner: Send + Sync {
+    fn run<'a>(&'a self, command: &'a ActionCommand) -> BoxFuture<'a, Result<ActionOutput>>;
+}
+
+/// Runs action commands as processes on this system.
+pub struct SystemActionRunner;
+
+impl ActionRunner for SystemActionRunner {
+    fn run<'a>(&'a self, command: &'a ActionCommand) -> BoxFuture<'a, Result<ActionOutput>> {
+        Box::pin(async move {
+            let mut process = Command::new(&command.program);
+            process
+                .args(&command.args)
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::inherit())
+                .kill_on_drop(true);
+
+            if let Some(user_name) = &command.user {
+                let user = uzers::get_user_by_name(user_name)
+                    .with_context(|| format!("User `{}` does not exist.", user_name))?;
+                process
+                    .uid(user.uid())
+                    .gid(user.primary_group_id())
+                    .env("HOME", user.home_dir())
+                    .env("USER", user_name)
+                    .env("LOGNAME", user_name);
+            }
+
+            let mut child = process
+                .spawn()
+                .with_context(|| format!("Failed to run `{}`.", command.program))?;
+            let mut stdout = child.stdout.take().context("The output of the command is not piped.")?;
+
+            let run = async {
+                let mut output = Vec::with_capacity(MAX_STDOUT_LEN);
+                (&mut stdout).take(MAX_STDOUT_LEN as u64).read_to_end(&mut output).await?;
+                // The rest of the output is thrown away rather than left in the pipe, which
+                // would block the command once it is full.
+                io::copy(&mut stdout, &mut io::sink()).await?;
+                let status = child.wait().await?;
+                Ok::<_, std::io::Error>((status, output))
+            };
+
+            // Dropping the child when the timeout elapses kills it.
+            let Ok(result) = time::timeout(command.timeout, run).await else {
+                return Ok(ActionOutput {
+                    exit_code: None,
+                    stdout: String::new(),
+                    timed_out: true,
+                });
+            };
+            let (status, output) = result.with_context(|| format!("Failed to wait for `{}`.", command.program))?;
+
+            Ok(ActionOutput {
+                exit_code: status.code(),
+                stdout: truncate_output(&output),
+                timed_out: false,
+            })
+        })
+    }
+}
+
+/// A single action exposed as a button entity.
+struct Action {
+    entity_id: String,
+    icon: String,
+    device_class: Option<&'static str>,
+    command: ActionCommand,
+}
+
+/// The actions enabled in the configuration, exposed to Home Assistant as buttons.
+pub struct Actions {
+    runner: Arc<dyn ActionRunner>,
+    results: UnboundedSender<ActionResult>,
+    actions: HashMap<String, Action>,
+}
+
+impl Actions {
+    /// Collect every action enabled in the configuration.
+    ///
+    /// The result of every action that is run is sent to `results`.
+    pub fn new(config: &Config, runner: Arc<dyn ActionRunner>, results: UnboundedSender<ActionResult>) -> Result<Self> {
+        Ok(Self {
+            runner,
+            results,
+            actions: build_actions(config)?,
+        })
+    }
+
+    /// Replace the actions with the ones enabled in a reloaded configuration.
+    pub fn reload(&mut self, config: &Config) -> Result<()> {
+        self.actions = build_actions(config)?;
+        Ok(())
+    }
+
+    /// Register a button entity for every action.
+    pub async fn register(&self, home_assistant: &mut HomeAssistant) -> Result<()> {
+        for action in self.actions.values() {
+            let mut builder = EntityRegistrationBuilder::new("button", &action.entity_id)
+                .icon(&action.icon);
+            if let Some(device_class) = action.device_class {
+                builder = builder.device_class(device_class);
+            }
+
+            home_assistant
+                .register_entity_with_builder(builder)
+                .await
+                .with_context(|| format!("Failed to register the button of action `{}`.", action.entity_id))?;
+        }
+
+        Ok(())
+    }
+
+    /// Run the action behind a pressed button in the background.
+    pub fn trigger(&self, entity_id: &str) {
+        let Some(action) = self.actions.get(entity_id) else {
+            log::warn!("Button `{}` has no action.", entity_id);
+            return;
+        };
+
+        log::info!("Button `{}` pressed, running `{}`.", entity_id, action.command.program);
+
+        let runner = self.runner.clone();
+        let results = self.results.clone();
+        let entity_id = entity_id.to_string();
+        let command = action.command.clone();
+        tokio::spawn(async move {
+            let result = match runner.run(&command).await {
+                Ok(output) => {
+                    if output.timed_out {
+                        log::error!("Action `{}` timed out after {:?}.", entity_id, command.timeout);
+                    } else if output.exit_code == Some(0) {
+                        log::info!("Action `{}` completed.", entity_id);
+                    } else {
+                        log::error!("Action `{}` failed with exit code {:?}.", entity_id, output.exit_code);
+                    }
+
+                    ActionResult {
+                        entity_id,
+                        exit_code: output.exit_code,
+                        stdout: output.stdout,
+                        timed_out: output.timed_out,
+                        error: None,
+                    }
+                }
+                Err(error) => {
+                    log::error!("Action `{}` failed: {:#}", entity_id, error);
+                    ActionResult {
+                        entity_id,
+                        exit_code: None,
+                        stdout: String::new(),
+                        timed_out: false,
+                        error: Some(format!("{:#}", error)),
+                    }
+                }
+            };
+
+            // The receiver is only gone while the daemon is shutting down.
+            let _ = results.send(result);
+        });
+    }
+}
+
+/// Collect every power and user-defined action enabled in the configuration, keyed by entity ID.
+fn build_actions(config: &Config) -> Result<HashMap<String, Action>> {
+    let power = &config.power;
+    let power_actions = [
+        ("reboot", &power.reboot, "mdi:restart", Some("restart"), &["systemctl", "reboot"][..]),
+        ("shutdown", &power.shutdown, "mdi:power", None, &["systemctl", "poweroff"][..]),
+        ("suspend", &power.suspend, "mdi:power-sleep", None, &["systemctl", "suspend"][..]),
+        ("hibernate", &power.hibernate, "mdi:snowflake", None, &["systemctl", "hibernate"][..]),
+        ("lock", &power.lock, "mdi:lock", None, &["loginctl", "lock-sessions"][..]),
+    ];
+
+    let mut actions = HashMap::new();
+    for (name, action_config, icon, device_class, default_command) in power_actions {
+        if let Some(command) = power_action_command(action_config, default_command)
+            .with_context(|| format!("Invalid command for the `{}` power action.", name))?
+        {
+            let entity_id = format!("power_{}", name);
+            actions.insert(entity_id.clone(), Action {
+                entity_id,
+                icon: icon.to_string(),
+                device_class,
+                command,
+            });
+        }
+    }
+
+    for action_config in &config.actions {
+        let action = custom_action(action_config);
+        if actions.contains_key(&action.entity_id) {
+            bail!("There is more than one action named `{}`.", action.entity_id);
+        }
+        actions.insert(action.entity_id.clone(), action);
+    }
+
+    Ok(actions)
+}
+
+/// The command of a power action, or `None` if the action is disabled.
+fn power_action_command(config: &PowerActionConfig, default_command: &[&str]) -> Result<Option<ActionCommand>> {
+    if !config.enabled {
+        return Ok(None);
+    }
+
+    match &config.command {
+        Some(command) => ActionCommand::from_argv(command).map(Some),
+        None => {
+            let command: Vec<String> = default_command.iter().map(|arg| arg.to_string()).collect();
+            ActionCommand::from_argv(&command).map(Some)
+        }
+    }
+}
+
+fn custom_action(config: &ActionConfig) -> Action {
+    Action {
+        entity_id: config.name.clone(),
+        icon: config.icon.clone().unwrap_or_else(|| "mdi:script-text-play".to_string()),
+        device_class: None,
+        command: ActionCommand {
+            program: config.command.clone(),
+            args: config.args.clone(),
+            timeout: config.timeout.unwrap_or(Duration::from_secs(60)),
+            user: config.user.clone(),
+        },
+    }
+}
+
+/// Decode the output of a command, cutting it off after [`MAX_STDOUT_LEN`] bytes.
+fn truncate_output(output: &[u8]) -> String {
+    // Reading stops after a fixed number of bytes, which may cut off the last character.
+    let output = match std::str::from_utf8(output) {
+        Err(error) if error.error_len().is_none() => &output[..error.valid_up_to()],
+        _ => output,
+    };
+
+    let mut output = String::from_utf8_lossy(output).into_owned();
+    if output.len() > MAX_STDOUT_LEN {
+        let mut end = MAX_STDOUT_LEN;
+        while !output.is_char_boundary(end) {
+            end -= 1;
+        }
+        output.truncate(end);
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PowerConfig;
+    use std::sync::Mutex;
+    use tokio::sync::mpsc;
+
+    /// Pretends to run commands, taking as long as the number of seconds in their first
+    /// argument, and fails to start programs called `missing`.
+    #[derive(Default)]
+    struct FakeRunner {
+        commands: Mutex<Vec<String>>,
+    }
+
+    impl ActionRunner for FakeRunner {
+        fn run<'a>(&'a self, command: &'a ActionCommand) -> BoxFuture<'a, Result<ActionOutput>> {
+            Box::pin(async move {
+                self.commands.lock().unwrap().push(command.program.clone());
+                if command.program == "missing" {
+                    bail!("Failed to run `{}`.", command.program);
+                }
+
+                let duration = Duration::from_secs(command.args[0].parse()?);
+                Ok(ActionOutput {
+                    exit_code: (duration <= command.timeout).then_some(0),
+                    stdout: format!("ran {}", command.program),
+                    timed_out: duration > command.timeout,
+                })
+            })
+        }
+    }
+
+    fn action(name: &str, program: &str, seconds: u64) -> ActionConfig {
+        ActionConfig {
+            name: name.to_string(),
+            command: program.to_string(),
+            args: vec![seconds.to_string()],
+            timeout: Some(Duration::from_secs(10)),
+            user: None,
+            icon: None,
+        }
+    }
+
+    async fn trigger(entity_id: &str) -> (ActionResult, Vec<String>) {
+        let config = Config {
+            actions: vec![
+                action("backup", "backup.sh", 1),
+                action("slow", "sleep", 60),
+                action("broken", "missing", 1),
+            ],
+            ..Config::default()
+        };
+        let runner = Arc::new(FakeRunner::default());
+        let (results_tx, mut results) = mpsc::unbounded_channel();
+        let actions = Actions::new(&config, runner.clone(), results_tx).unwrap();
+
+        actions.trigger(entity_id);
+        let result = results.recv().await.unwrap();
+        let commands = runner.commands.lock().unwrap().clone();
+        (result, commands)
+    }
+
+    #[tokio::test]
+    async fn sends_result_of_completed_action() {
+        let (result, commands) = trigger("backup").await;
+
+        assert_eq!(commands, ["backup.sh"]);
+        assert_eq!(result.entity_id, "backup");
+        assert_eq!(result.exit_code, Some(0));
+        assert_eq!(result.stdout, "ran backup.sh");
+        assert!(!result.timed_out);
+        assert!(result.error.is_none());
+    }
+
+    #[tokio::test]
+    async fn sends_result_of_timed_out_action() {
+        let (result, _) = trigger("slow").await;
+
+        assert_eq!(result.entity_id, "slow");
+        assert_eq!(result.exit_code, None);
+        assert!(result.timed_out);
+        assert!(result.error.is_none());
+    }
+
+    #[tokio::test]
+    async fn sends_error_of_action_that_cannot_start() {
+        let (result, _) = trigger("broken").await;
+
+        assert_eq!(result.entity_id, "broken");
+        assert_eq!(result.exit_code, None);
+        assert!(!result.timed_out);
+        assert_eq!(result.error.as_deref(), Some("Failed to run `missing`."));
+        assert_eq!(
+            serde_json::to_value(&result).unwrap(),
+            serde_json::json!({
+                "exit_code": null,
+                "stdout": "",
+                "timed_out": false,
+                "error": "Failed to run `missing`.",
+            })
+        );
+    }
+
+    fn enabled(command: Option<&[&str]>) -> PowerActionConfig {
+        PowerActionConfig {
+            enabled: true,
+            command: command.map(|command| command.iter().map(|arg| arg.to_string()).collect()),
+        }
+    }
+
+    fn command_line(actions: &HashMap<String, Action>, entity_id: &str) -> Vec<String> {
+        let command = &actions[entity_id].command;
+        std::iter::once(command.program.clone()).chain(command.args.iter().cloned()).collect()
+    }
+
+    #[test]
+    fn power_actions_are_disabled_by_default() {
+        let actions = build_actions(&Config::default()).unwrap();
+
+        assert!(actions.is_empty());
+    }
+
+    #[test]
+    fn power_actions_run_their_default_commands() {
+        let config = Config {
+            power: PowerConfig {
+                reboot: enabled(None),
+                shutdown: enabled(None),
+                suspend: enabled(None),
+                hibernate: enabled(None),
+                lock: enabled(None),
+            },
+            ..Config::default()
+        };
+        let actions = build_actions(&config).unwrap();
+
+        assert_eq!(actions.len(), 5);
+        assert_eq!(command_line(&actions, "power_reboot"), ["systemctl", "reboot"]);
+        assert_eq!(command_line(&actions, "power_shutdown"), ["systemctl", "poweroff"]);
+        assert_eq!(command_line(&actions, "power_suspend"), ["systemctl", "suspend"]);
+        assert_eq!(command_line(&actions, "power_hibernate"), ["systemctl", "hibernate"]);
+        assert_eq!(command_line(&actions, "power_lock"), ["loginctl", "lock-sessions"]);
+        assert_eq!(actions["power_reboot"].device_class, Some("restart"));
+        assert!(actions["power_reboot"].command.user.is_none());
+    }
+
+    #[test]
+    fn power_action_runs_configured_command() {
+        let config = Config {
+            power: PowerConfig {
+                reboot: enabled(Some(&["/sbin/reboot", "--force"])),
+                ..PowerConfig::default()
+            },
+            ..Config::default()
+        };
+        let actions = build_actions(&config).unwrap();
+
+        assert_eq!(actions.len(), 1);
+        assert_eq!(command_line(&actions, "power_reboot"), ["/sbin/reboot", "--force"]);
+    }
+
+    #[test]
+    fn rejects_power_action_with_empty_command() {
+        let config = Config {
+            power: PowerConfig {
+                shutdown: enabled(Some(&[])),
+                ..PowerConfig::default()
+            },
+            ..Config::default()
+        };
+        let error = build_actions(&config).err().unwrap();
+
+        assert_eq!(
+            format!("{:#}", error),
+            "Invalid command for the `shutdown` power action.: An action command cannot be empty"
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_start_of_long_output() {
+        let command = ActionCommand {
+            program: "head".to_string(),
+            args: vec!["-c".to_string(), "100000".to_string(), "/dev/zero".to_string()],
+            timeout: Duration::from_secs(10),
+            user: None,
+        };
+        let output = SystemActionRunner.run(&command).await.unwrap();
+
+        assert_eq!(output.exit_code, Some(0));
+        assert_eq!(output.stdout, "\0".repeat(MAX_STDOUT_LEN));
+        assert!(!output.timed_out);
+    }
+
+    #[test]
+    fn drops_character_cut_off_at_end_of_output() {
+        let output = "é".as_bytes();
+
+        assert_eq!(truncate_output(&output[..1]), "");
+        assert_eq!(truncate_output(output), "é");
+    }
+
+    #[tokio::test]
+    async fn ignores_buttons_without_action() {
+        let (results_tx, mut results) = mpsc::unbounded_channel();
+        let runner = Arc::new(FakeRunner::default());
+        let actions = Actions::new(&Config::default(), runner.clone(), results_tx).unwrap();
+
+        actions.trigger("unknown");
+        drop(actions);
+
+        assert!(results.recv().await.is_none());
+        assert!(runner.commands.lock().unwrap().is_empty());
+    }
+}
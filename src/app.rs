@@ -1,19 +1,21 @@
 use anyhow::{Context, Result};
+use futures_util::future::join_all;
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::{mpsc, watch};
 use tokio::task::JoinHandle;
 use tokio::time::Instant;
 use tokio::time;
-use sysinfo::System;
-use battery::Manager;
 use tokio_util::sync::CancellationToken;
 
+use crate::actions::{ActionResult, Actions, SystemActionRunner};
+use crate::collector::{build_collectors, CollectorFailures, CollectorUpdate, ScheduledCollector};
 use crate::config::Config;
-use crate::home_assistant::HomeAssistant;
-use crate::lm_sensors_impl::SensorsImpl;
-use crate::nvidia_gpu::NvidiaGpuSensors;
-use crate::system_sensors::{collect_system_stats, register_system_sensors};
+use crate::discovery_state::TopicOwners;
+use crate::home_assistant::{attributes_id, EntityRegistrationBuilder, HomeAssistant};
+use crate::mqtt::MqttEvent;
+use crate::utils::with_jitter;
 
 /// Main application structure that manages the System MQTT daemon.
 /// 
@@ -21,13 +23,13 @@ use crate::system_sensors::{collect_system_stats, register_system_sensors};
 /// including system statistics collection, MQTT communication, and sensor management.
 pub struct App {
     config: Config,
-    system: System,
+    config_rx: watch::Receiver<Config>,
     home_assistant: HomeAssistant,
-    sensors: SensorsImpl,
-    gpu_sensors: NvidiaGpuSensors,
-    battery_manager: Manager,
-    drive_list: HashMap<PathBuf, String>,
-    mqtt_task: JoinHandle<std::result::Result<(), rumqttc::ConnectionError>>,
+    collectors: Vec<ScheduledCollector>,
+    actions: Actions,
+    action_results: mpsc::UnboundedReceiver<ActionResult>,
+    mqtt_task: JoinHandle<()>,
+    mqtt_events: mpsc::UnboundedReceiver<MqttEvent>,
     cancel_token: CancellationToken,
 }
 
@@ -35,62 +37,55 @@ impl App {
     /// Create a new instance of the System MQTT daemon.
     /// 
     /// This initializes all components including:
-    /// - System monitoring
     /// - MQTT client
     /// - Home Assistant integration
-    /// - Hardware sensors
-    /// - Battery monitoring
+    /// - Every collector enabled in the configuration, leaving out those that fail to start
+    /// - Every action enabled in the configuration
     /// 
     /// # Arguments
     /// 
-    /// * `config` - The configuration for the daemon
+    /// * `config_rx` - The configuration for the daemon, updated whenever it is reloaded
     /// * `cancel_token` - Token used for graceful shutdown
     /// 
     /// # Returns
     /// 
     /// A new App instance ready to run, or an error if initialization fails.
-    pub async fn new(config: Config, cancel_token: CancellationToken) -> Result<Self> {
-        let mut system = System::new_all();
-        let hostname = System::host_name().context("Could not get system hostname.")?;
-        let device_id = config.unique_id.clone().unwrap_or_else(|| hostname);
+    pub async fn new(mut config_rx: watch::Receiver<Config>, cancel_token: CancellationToken) -> Result<Self> {
+        let config = config_rx.borrow_and_update().clone();
+        let device_id = match &config.unique_id {
+            Some(unique_id) => unique_id.clone(),
+            None => crate::device_info::default_device_id()?,
+        };
 
         // Setup MQTT client
         let (client, eventloop) = crate::mqtt::setup_mqtt_client(&config, &device_id).await?;
-        let manager = battery::Manager::new().context("Failed to initialize battery monitoring.")?;
 
-        let mut home_assistant = HomeAssistant::new(device_id, client)?;
+        let (connection_tx, connection_rx) = watch::channel(false);
+        let mut home_assistant = HomeAssistant::new(device_id, client, connection_rx, &config)?;
 
-        // Register system sensors
-        register_system_sensors(&mut home_assistant, &config).await?;
+        let mut collector_failures = CollectorFailures::new();
+        let mut collectors = build_collectors(&config, &mut collector_failures).await;
+        register_collectors(&mut home_assistant, &mut collectors, &mut collector_failures).await?;
+        register_collector_failures(&mut home_assistant, &collector_failures).await?;
 
-        let mut sensors = SensorsImpl::new()?;
-        sensors.register_sensors(&mut home_assistant).await?;
+        let (action_results_tx, action_results) = mpsc::unbounded_channel();
+        let actions = Actions::new(&config, Arc::new(SystemActionRunner), action_results_tx)?;
+        actions.register(&mut home_assistant).await?;
+        home_assistant.save_entity_ids().await;
+        home_assistant.retract_stale_discovery(TopicOwners::new(), &collector_failures).await?;
 
-        let mut gpu_sensors = NvidiaGpuSensors::new();
-        gpu_sensors.init().await?;
-        gpu_sensors.register_sensors(&mut home_assistant).await?;
-
-        home_assistant.set_available(true).await?;
-
-        let mqtt_task = crate::mqtt::mqtt_loop(eventloop).await;
-
-        let drive_list: HashMap<PathBuf, String> = config
-            .drives
-            .iter()
-            .map(|drive_config| (drive_config.path.clone(), drive_config.name.clone()))
-            .collect();
-
-        system.refresh_all();
+        let (mqtt_events_tx, mqtt_events) = mpsc::unbounded_channel();
+        let mqtt_task = crate::mqtt::mqtt_loop(eventloop, mqtt_events_tx, connection_tx).await;
 
         Ok(Self {
             config,
-            system,
+            config_rx,
             home_assistant,
-            sensors,
-            gpu_sensors,
-            battery_manager: manager,
-            drive_list,
+            collectors,
+            actions,
+            action_results,
             mqtt_task,
+            mqtt_events,
             cancel_token,
         })
     }
@@ -98,39 +93,84 @@ impl App {
     /// Run the main daemon loop.
     /// 
     /// This method runs the main loop that:
-    /// - Collects system statistics at configured intervals
+    /// - Collects system statistics, each collector at its own interval
     /// - Publishes updates to MQTT
     /// - Sends Home Assistant discovery messages
+    /// - Runs the actions behind pressed buttons and publishes their results
+    /// - Applies reloaded configurations
     /// - Handles graceful shutdown
     /// 
     /// The loop continues until either:
-    /// - The MQTT connection fails
+    /// - The MQTT task stops
     /// - A shutdown signal is received
+    /// - A reloaded configuration changes the MQTT connection settings
     /// - An unrecoverable error occurs
     /// 
     /// # Returns
     /// 
-    /// Returns Ok(()) if the daemon shuts down gracefully, or an error if something goes wrong.
-    pub async fn run(&mut self) -> Result<()> {
+    /// Returns how the daemon stopped, or an error if something goes wrong.
+    pub async fn run(&mut self) -> Result<RunOutcome> {
+        loop {
+            match self.run_loop().await? {
+                LoopExit::Shutdown => break,
+                LoopExit::Reload => {
+                    let config = self.config_rx.borrow_and_update().clone();
+                    if !self.config.same_broker(&config) {
+                        self.cleanup().await?;
+                        return Ok(RunOutcome::Reconnect);
+                    }
+                    self.reload(config).await?;
+                }
+            }
+        }
+
+        self.cleanup().await?;
+        Ok(RunOutcome::Shutdown)
+    }
+
+    async fn run_loop(&mut self) -> Result<LoopExit> {
+        // Every collector is sampled on a task of its own at its own interval, so a slow one
+        // does not hold back the others or the handling of MQTT events. They are handed back
+        // once the loop exits, so that they can be shut down or rebuilt.
+        let (updates_tx, updates_rx) = mpsc::unbounded_channel();
+        let stop = CancellationToken::new();
+        let tasks: Vec<_> = self
+            .collectors
+            .drain(..)
+            .map(|collector| collector.spawn(updates_tx.clone(), stop.clone()))
+            .collect();
+
+        let result = self.handle_events(updates_rx).await;
+
+        stop.cancel();
+        for task in join_all(tasks).await {
+            match task {
+                Ok(collector) => self.collectors.push(collector),
+                Err(error) => log::error!("A collector task failed: {:#}", error),
+            }
+        }
+
+        result
+    }
+
+    async fn handle_events(&mut self, mut updates_rx: mpsc::UnboundedReceiver<CollectorUpdate>) -> Result<LoopExit> {
         let mut discovery_interval = time::interval_at(
             Instant::now(),
             self.config
                 .discovery_interval
                 .unwrap_or(Duration::from_secs(60 * 60)),
         );
-        let mut update_interval = time::interval_at(Instant::now(), self.config.update_interval);
+
+        // When Home Assistant comes online, the device is announced again after a short delay.
+        let mut announce_at = None;
 
         loop {
             tokio::select! {
                 result = &mut self.mqtt_task => {
                     match result {
-                        Ok(Ok(_)) => {
+                        Ok(()) => {
                             log::info!("MQTT task completed successfully, exiting.");
-                            break;
-                        }
-                        Ok(Err(e)) => {
-                            log::error!("MQTT task failed: {:#}", e);
-                            return Err(e).context("MQTT task failed.");
+                            return Ok(LoopExit::Shutdown);
                         }
                         Err(e) => {
                             log::error!("MQTT task failed: {:#}", e);
@@ -138,38 +178,188 @@ impl App {
                         }
                     }
                 }
-                _ = discovery_interval.tick() => {
+                Some(event) = self.mqtt_events.recv() => {
+                    match event {
+                        MqttEvent::Connected => self.home_assistant.set_connected(true).await?,
+                        MqttEvent::Disconnected => self.home_assistant.set_connected(false).await?,
+                        MqttEvent::Message(message) => {
+                            if let Some(entity_id) = self.home_assistant.pressed_button(&message) {
+                                self.actions.trigger(entity_id);
+                            } else if self.home_assistant.is_birth_message(&message) {
+                                // Spread out the announcements of many devices sharing one broker.
+                                let delay = with_jitter(Duration::from_secs(3), 1.0);
+                                log::info!("Home Assistant came online, announcing device in {:.1?}.", delay);
+                                announce_at = Some(Instant::now() + delay);
+                            } else {
+                                self.home_assistant.retract_retired_entity(&message).await?;
+                            }
+                        }
+                    }
+                }
+                _ = time::sleep_until(announce_at.unwrap_or_else(Instant::now)), if announce_at.is_some() => {
+                    announce_at = None;
+                    if self.home_assistant.is_connected() {
+                        self.home_assistant.announce().await?;
+                    }
+                }
+                _ = time::sleep_until(self.home_assistant.retiring_until().unwrap_or_else(Instant::now)),
+                    if self.home_assistant.retiring_until().is_some() => {
+                    self.home_assistant.finish_retiring().await?;
+                }
+                _ = discovery_interval.tick(), if self.home_assistant.is_connected() => {
                     self.home_assistant.publish_discovery().await?
                 }
-                _ = update_interval.tick() => {
-                    let stats = collect_system_stats(
-                        &mut self.system,
-                        &self.drive_list,
-                        &self.battery_manager,
-                        &mut self.sensors,
-                        &self.gpu_sensors,
-                    ).await?;
-
-                    let json_message = serde_json::to_string(&stats)
-                        .context("Failed to serialize stats to JSON.")?;
-                    self.home_assistant.publish("state", json_message).await;
+                Some(update) = updates_rx.recv() => {
+                    match update {
+                        CollectorUpdate::Values(stats) => self.home_assistant.update_state(stats).await?,
+                        CollectorUpdate::Availability { collector, available } => {
+                            self.home_assistant.set_collector_available(&collector, available).await?
+                        }
+                    }
+                }
+                Some(result) = self.action_results.recv() => {
+                    self.home_assistant.publish_action_result(&result).await?;
+                }
+                Ok(()) = self.config_rx.changed() => {
+                    return Ok(LoopExit::Reload);
                 }
                 _ = self.cancel_token.cancelled() => {
                     log::info!("Shutdown signal received, exiting...");
-                    break;
+                    return Ok(LoopExit::Shutdown);
                 }
             }
         }
+    }
 
-        self.cleanup().await?;
+    /// Apply a reloaded configuration that keeps the MQTT connection settings.
+    ///
+    /// All collectors and actions are rebuilt and registered again, and the discovery topics of
+    /// entities that no longer exist are retracted from Home Assistant.
+    async fn reload(&mut self, config: Config) -> Result<()> {
+        log::info!("Applying reloaded configuration.");
+
+        self.shutdown_collectors().await;
+        // libsensors can only be initialized once at a time, so the old collectors must be
+        // dropped before the new ones are built.
+        self.collectors.clear();
+        let previous_topics = self.home_assistant.reset(&config);
+
+        let mut collector_failures = CollectorFailures::new();
+        self.collectors = build_collectors(&config, &mut collector_failures).await;
+        register_collectors(&mut self.home_assistant, &mut self.collectors, &mut collector_failures).await?;
+        register_collector_failures(&mut self.home_assistant, &collector_failures).await?;
+
+        self.actions.reload(&config)?;
+        self.actions.register(&mut self.home_assistant).await?;
+
+        self.home_assistant.save_entity_ids().await;
+        self.home_assistant.retract_stale_discovery(previous_topics, &collector_failures).await?;
+
+        if self.home_assistant.is_connected() {
+            self.home_assistant.subscribe().await?;
+        }
+
+        self.config = config;
         Ok(())
     }
 
+    async fn shutdown_collectors(&mut self) {
+        for ScheduledCollector { collector, .. } in &mut self.collectors {
+            if let Err(error) = collector.shutdown().await {
+                log::error!("Error while shutting down `{}` collector: {:#}", collector.name(), error);
+            }
+        }
+    }
+
     async fn cleanup(&mut self) -> Result<()> {
-        if let Err(error) = self.home_assistant.set_available(false).await {
-            log::error!("Error while disconnecting from home assistant: {:#}", error);
+        self.shutdown_collectors().await;
+
+        if self.home_assistant.is_connected() && !self.mqtt_task.is_finished() {
+            if let Err(error) = self.home_assistant.set_available(false).await {
+                log::error!("Error while disconnecting from home assistant: {:#}", error);
+            }
+            self.home_assistant.disconnect().await?;
+
+            // Give the MQTT task a chance to flush the last messages before it is stopped.
+            let _ = time::timeout(Duration::from_secs(5), &mut self.mqtt_task).await;
         }
-        self.home_assistant.disconnect().await?;
+
+        self.mqtt_task.abort();
         Ok(())
     }
-} 
\ No newline at end of file
+}
+
+/// How [`App::run`] stopped.
+pub enum RunOutcome {
+    /// The daemon was asked to shut down.
+    Shutdown,
+    /// The MQTT connection settings changed and the daemon must reconnect.
+    Reconnect,
+}
+
+/// Why the main loop of [`App::run_loop`] stopped.
+enum LoopExit {
+    Shutdown,
+    Reload,
+}
+
+/// Register the entities of every collector with Home Assistant.
+///
+/// Each entity references the availability topic of the collector that registered it. A
+/// collector that fails to register is dropped and recorded in `failures`, and the entities
+/// it did register are marked unavailable.
+async fn register_collectors(
+    home_assistant: &mut HomeAssistant,
+    collectors: &mut Vec<ScheduledCollector>,
+    failures: &mut CollectorFailures,
+) -> Result<()> {
+    let mut registered = Vec::with_capacity(collectors.len());
+    for mut scheduled in collectors.drain(..) {
+        let collector = &mut scheduled.collector;
+        home_assistant.set_registering_collector(Some(collector.name()));
+        match collector.register(home_assistant).await {
+            Ok(()) => registered.push(scheduled),
+            Err(error) => {
+                let name = collector.name().to_string();
+                failures.record(&name, error.context("Failed to register its entities."));
+                home_assistant.set_collector_available(&name, false).await?;
+            }
+        }
+    }
+    home_assistant.set_registering_collector(None);
+    *collectors = registered;
+
+    Ok(())
+}
+
+/// Register the diagnostic entity reporting the collectors that could not be started, and
+/// publish its state.
+///
+/// Its value is the number of failed collectors, and its attributes tell why each one failed.
+async fn register_collector_failures(
+    home_assistant: &mut HomeAssistant,
+    failures: &CollectorFailures,
+) -> Result<()> {
+    home_assistant
+        .register_entity_with_builder(
+            EntityRegistrationBuilder::new("sensor", "failed_collectors")
+                .state_class("measurement")
+                .entity_category("diagnostic")
+                .icon("mdi:alert-circle-outline")
+                .json_attributes()
+        )
+        .await
+        .context("Failed to register failed collectors topic.")?;
+
+    let reasons = failures
+        .reasons()
+        .iter()
+        .map(|(collector, reason)| (collector.clone(), serde_json::Value::from(reason.as_str())))
+        .collect();
+    home_assistant
+        .update_state(HashMap::from([
+            ("failed_collectors".to_string(), serde_json::Value::from(failures.reasons().len())),
+            (attributes_id("failed_collectors"), serde_json::Value::Object(reasons)),
+        ]))
+        .await
+}
@@ -0,0 +1,265 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use anyhow::{anyhow, Context, Result};
+use futures_util::future::{BoxFuture, LocalBoxFuture};
+use serde_json::Value;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::JoinHandle;
+use tokio::time::{self, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+use crate::config::{CollectorConfig, Config, SensorsBackend};
+#[cfg(not(feature = "lm-sensors"))]
+use crate::config::missing_feature;
+use crate::home_assistant::HomeAssistant;
+use crate::hwmon::HwmonCollector;
+#[cfg(feature = "lm-sensors")]
+use crate::lm_sensors_impl::SensorsImpl;
+#[cfg(feature = "nvidia")]
+use crate::nvidia_gpu::NvidiaGpuSensors;
+use crate::sysfs_sensors::SysfsSensors;
+#[cfg(feature = "battery")]
+use crate::system_sensors::BatteryCollector;
+use crate::system_sensors::{DriveCollector, SystemCollector};
+
+/// A source of system statistics.
+///
+/// Each collector announces the entities it provides to Home Assistant once at startup
+/// and is then asked to sample their current values on every update tick. A collector
+/// that cannot sample all of its values, for example because its hardware went away,
+/// should return an error so that its entities are marked unavailable.
+///
+/// Every collector is sampled on a task of its own, so collecting must not block. Synchronous
+/// reads run on the blocking thread pool, for example through [`read_blocking`].
+pub trait Collector: Send {
+    /// The name of the collector, used in log messages and in its availability topic.
+    fn name(&self) -> &str;
+
+    /// Register the entities provided by this collector with Home Assistant.
+    fn register<'a>(&'a mut self, home_assistant: &'a mut HomeAssistant) -> LocalBoxFuture<'a, Result<()>>;
+
+    /// Sample the current values and insert them into `stats`, keyed by entity ID.
+    fn collect<'a>(&'a mut self, stats: &'a mut HashMap<String, Value>) -> BoxFuture<'a, Result<()>>;
+
+    /// Release any resources held by the collector before the daemon exits.
+    fn shutdown(&mut self) -> LocalBoxFuture<'_, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Run a synchronous read on the blocking thread pool, so that it does not hold up other tasks.
+pub async fn read_blocking<S, T>(
+    source: &Arc<Mutex<S>>,
+    read: impl FnOnce(&mut S) -> Result<T> + Send + 'static,
+) -> Result<T>
+where
+    S: Send + 'static,
+    T: Send + 'static,
+{
+    let source = Arc::clone(source);
+    tokio::task::spawn_blocking(move || {
+        let mut source = source.lock().map_err(|_| anyhow!("An earlier read panicked."))?;
+        read(&mut source)
+    })
+    .await
+    .context("Failed to run a blocking read.")?
+}
+
+/// A message from a running collector.
+pub enum CollectorUpdate {
+    /// A batch of freshly collected values, keyed by entity ID.
+    Values(HashMap<String, Value>),
+    /// The collector started or stopped being able to collect its values.
+    Availability { collector: String, available: bool },
+}
+
+/// The collectors that could not be started.
+#[derive(Default)]
+pub struct CollectorFailures {
+    /// Why the collectors that failed did so, by collector name.
+    reasons: BTreeMap<String, String>,
+    /// The collectors left out because their hardware is missing, without counting as failures.
+    missing_hardware: BTreeSet<String>,
+}
+
+impl CollectorFailures {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Disable a collector that could not be started, keeping the reason for the diagnostic
+    /// entity.
+    pub fn record(&mut self, collector: &str, error: anyhow::Error) {
+        log::error!("Disabling `{}` collector: {:#}", collector, error);
+        self.reasons.insert(collector.to_string(), format!("{:#}", error));
+    }
+
+    /// Disable a collector whose hardware is missing.
+    ///
+    /// This only counts as a failure if the collector was enabled explicitly. Otherwise it is
+    /// merely on by default, and most machines lack the hardware of some such collectors.
+    #[cfg_attr(not(any(feature = "battery", feature = "nvidia")), allow(dead_code))]
+    pub fn record_missing_hardware(&mut self, collector: &str, config: &CollectorConfig, reason: &str) {
+        if config.enabled == Some(true) {
+            self.record(collector, anyhow!("{}", reason));
+        } else {
+            log::info!("Skipping `{}` collector: {}", collector, reason);
+            self.missing_hardware.insert(collector.to_string());
+        }
+    }
+
+    /// Why the collectors that failed did so, by collector name.
+    pub fn reasons(&self) -> &BTreeMap<String, String> {
+        &self.reasons
+    }
+
+    /// Whether a collector was not started, because it failed or its hardware is missing.
+    pub fn contains(&self, collector: &str) -> bool {
+        self.reasons.contains_key(collector) || self.missing_hardware.contains(collector)
+    }
+}
+
+/// A collector together with the interval it is sampled at.
+pub struct ScheduledCollector {
+    pub collector: Box<dyn Collector>,
+    pub update_interval: Duration,
+}
+
+impl ScheduledCollector {
+    fn new(collector: impl Collector + 'static, update_interval: Duration) -> Self {
+        Self {
+            collector: Box::new(collector),
+            update_interval,
+        }
+    }
+
+    /// Sample the collector on a task of its own, handing it back once `stop` is cancelled.
+    pub fn spawn(mut self, updates: UnboundedSender<CollectorUpdate>, stop: CancellationToken) -> JoinHandle<Self> {
+        tokio::spawn(async move {
+            self.run(updates, stop).await;
+            self
+        })
+    }
+
+    /// Sample the collector on its own interval, forwarding every batch of values to `updates`.
+    ///
+    /// A collector that fails to collect is reported as unavailable until it succeeds again,
+    /// so its entities go unavailable in Home Assistant instead of keeping stale values.
+    /// This returns once `stop` is cancelled or the receiving end of `updates` is gone, but
+    /// never in the middle of collecting, so that the collector is not in use afterwards.
+    async fn run(&mut self, updates: UnboundedSender<CollectorUpdate>, stop: CancellationToken) {
+        let mut interval = time::interval(self.update_interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut was_available = None;
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                () = stop.cancelled() => return,
+            }
+
+            let mut stats = HashMap::new();
+            let result = self.collector.collect(&mut stats).await;
+            let available = result.is_ok();
+
+            if was_available != Some(available) {
+                // A collector that stays unavailable would otherwise log the same error on
+                // every tick.
+                if let Err(error) = result {
+                    log::warn!("Failed to collect `{}` statistics: {:#}", self.collector.name(), error);
+                }
+                was_available = Some(available);
+                let update = CollectorUpdate::Availability {
+                    collector: self.collector.name().to_string(),
+                    available,
+                };
+                if updates.send(update).is_err() {
+                    return;
+                }
+            }
+
+            if available && updates.send(CollectorUpdate::Values(stats)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Create every collector that is enabled in the configuration.
+///
+/// A collector whose hardware or library cannot be initialized is left out and recorded in
+/// `failures`, so that the others still run. Missing hardware only counts as a failure for
+/// collectors that were enabled explicitly. Collectors this build has no support for are
+/// left out silently, since the configuration is validated when it is loaded.
+pub async fn build_collectors(config: &Config, failures: &mut CollectorFailures) -> Vec<ScheduledCollector> {
+    let collectors_config = &config.collectors;
+    let mut collectors = Vec::new();
+
+    if collectors_config.system.is_enabled() {
+        collectors.push(ScheduledCollector::new(
+            SystemCollector::new(),
+            collectors_config.system.update_interval(config),
+        ));
+    }
+
+    #[cfg(feature = "battery")]
+    if collectors_config.battery.is_enabled() {
+        match BatteryCollector::new() {
+            Ok(Some(collector)) => collectors.push(ScheduledCollector::new(
+                collector,
+                collectors_config.battery.update_interval(config),
+            )),
+            Ok(None) => failures.record_missing_hardware("battery", &collectors_config.battery, "No battery found."),
+            Err(error) => failures.record("battery", error),
+        }
+    }
+
+    if collectors_config.drives.is_enabled() {
+        for drive in &config.drives {
+            collectors.push(ScheduledCollector::new(
+                DriveCollector::new(drive.clone()),
+                collectors_config.drives.update_interval(config),
+            ));
+        }
+    }
+
+    if collectors_config.lm_sensors.is_enabled() {
+        let update_interval = collectors_config.lm_sensors.update_interval(config);
+        let collector = match config.lm_sensors.backend {
+            #[cfg(feature = "lm-sensors")]
+            SensorsBackend::Libsensors => SensorsImpl::new()
+                .and_then(|sensors| HwmonCollector::new(sensors, &config.lm_sensors))
+                .map(|collector| ScheduledCollector::new(collector, update_interval)),
+            #[cfg(not(feature = "lm-sensors"))]
+            SensorsBackend::Libsensors => Err(missing_feature("lm_sensors.backend: libsensors", "lm-sensors")),
+            SensorsBackend::Sysfs => {
+                let sensors = SysfsSensors::new(&config.lm_sensors.sysfs_root());
+                HwmonCollector::new(sensors, &config.lm_sensors)
+                    .map(|collector| ScheduledCollector::new(collector, update_interval))
+            }
+        };
+        match collector {
+            Ok(collector) => collectors.push(collector),
+            Err(error) => failures.record("lm_sensors", error),
+        }
+    }
+
+    #[cfg(feature = "nvidia")]
+    if collectors_config.nvidia_gpu.is_enabled() {
+        let mut gpu_sensors = NvidiaGpuSensors::new();
+        match gpu_sensors.init().await {
+            Ok(true) => collectors.push(ScheduledCollector::new(
+                gpu_sensors,
+                collectors_config.nvidia_gpu.update_interval(config),
+            )),
+            Ok(false) => failures.record_missing_hardware(
+                "nvidia_gpu",
+                &collectors_config.nvidia_gpu,
+                "No NVIDIA GPU found.",
+            ),
+            Err(error) => failures.record("nvidia_gpu", error),
+        }
+    }
+
+    collectors
+}
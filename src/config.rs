@@ -1,5 +1,6 @@
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 use tokio::fs;
@@ -11,6 +12,7 @@ pub async fn load_config(path: &Path) -> anyhow::Result<Config> {
 
         let config: Config = serde_yaml::from_str(&fs::read_to_string(path).await?)
             .context("Failed to deserialize config file.")?;
+        config.validate()?;
 
         Ok(config)
     } else {
@@ -32,12 +34,23 @@ pub async fn load_config(path: &Path) -> anyhow::Result<Config> {
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
     /// The unique ID of the device.
-    /// If not specified, the hostname will be used.
+    /// If not specified, an ID derived from `/etc/machine-id` will be used, so that renaming
+    /// the host does not create a new device.
     /// This ID is used to identify the device in Home Assistant and MQTT topics.
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub unique_id: Option<String>,
 
+    /// A link to a web interface for managing the device, shown on its page in Home Assistant.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub configuration_url: Option<Url>,
+
+    /// The area Home Assistant suggests for the device when it is first discovered.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suggested_area: Option<String>,
+
     /// The URL of the MQTT server to connect to.
     /// Format: `mqtt://hostname:port` or `mqtts://hostname:port` for secure connections.
     pub mqtt_server: Url,
@@ -53,7 +66,8 @@ pub struct Config {
     pub password_source: PasswordSource,
 
     /// The interval at which system statistics are collected and published.
-    /// This determines how frequently the daemon will report system metrics.
+    /// This determines how frequently the daemon will report system metrics,
+    /// unless a collector specifies its own `update_interval`.
     pub update_interval: Duration,
 
     /// The interval at which Home Assistant discovery messages are sent.
@@ -62,6 +76,38 @@ pub struct Config {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub discovery_interval: Option<Duration>,
 
+    /// How entity states are published.
+    /// Defaults to a single combined JSON message for all entities.
+    #[serde(default)]
+    pub state_topic_mode: StateTopicMode,
+
+    /// Controls when unchanged values are published again.
+    /// By default every collected value is published.
+    #[serde(default)]
+    pub publish: PublishConfig,
+
+    /// Overrides for the metadata of individual entities, keyed by entity ID.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub entities: HashMap<String, EntityConfig>,
+
+    /// The topic Home Assistant announces itself on when it comes online.
+    /// Discovery, availability and the latest state are published again whenever it does.
+    /// If not specified, defaults to `homeassistant/status`.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub homeassistant_status_topic: Option<String>,
+
+    /// Buttons in Home Assistant that control the power state of the device.
+    /// Every button is disabled by default.
+    #[serde(default)]
+    pub power: PowerConfig,
+
+    /// Commands that can be run from Home Assistant, each exposed as a button.
+    /// Only the commands listed here can ever be run.
+    #[serde(default)]
+    pub actions: Vec<ActionConfig>,
+
     /// The list of drives to monitor for disk usage.
     /// Each drive configuration specifies a mount point and a name for reporting.
     pub drives: Vec<DriveConfig>,
@@ -71,6 +117,68 @@ pub struct Config {
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ca_cert: Option<PathBuf>,
+
+    /// The directory where state that must survive restarts is kept.
+    /// If not specified, defaults to `/var/lib/system-mqtt`.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_dir: Option<PathBuf>,
+
+    /// Keep state messages on disk while the MQTT server is unreachable and publish
+    /// them once the connection is back.
+    /// If not specified, messages produced while disconnected are dropped.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offline_queue: Option<OfflineQueueConfig>,
+
+    /// Which sources of statistics are enabled.
+    /// Every collector is enabled by default.
+    #[serde(default)]
+    pub collectors: CollectorsConfig,
+
+    /// Settings for the lm-sensors collector.
+    #[serde(default)]
+    pub lm_sensors: LmSensorsConfig,
+}
+
+impl Config {
+    /// The directory where state that must survive restarts is kept.
+    pub fn state_dir(&self) -> PathBuf {
+        self.state_dir
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("/var/lib/system-mqtt"))
+    }
+
+    /// Check that every option in use is supported by this build.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.username.is_some() && self.password_source == PasswordSource::Keyring && !cfg!(feature = "keyring") {
+            return Err(missing_feature("password_source: keyring", "keyring"));
+        }
+        if self.collectors.battery.enabled == Some(true) && !cfg!(feature = "battery") {
+            return Err(missing_feature("collectors.battery", "battery"));
+        }
+        if self.collectors.nvidia_gpu.enabled == Some(true) && !cfg!(feature = "nvidia") {
+            return Err(missing_feature("collectors.nvidia_gpu", "nvidia"));
+        }
+        if self.lm_sensors.backend == SensorsBackend::Libsensors && !cfg!(feature = "lm-sensors") {
+            return Err(missing_feature("lm_sensors.backend: libsensors", "lm-sensors"));
+        }
+        for action in &self.actions {
+            crate::home_assistant::validate_entity_id(&action.name)
+                .with_context(|| format!("Invalid name of action `{}`.", action.name))?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether both configurations connect to the MQTT server in the same way.
+    pub fn same_broker(&self, other: &Config) -> bool {
+        self.unique_id == other.unique_id
+            && self.mqtt_server == other.mqtt_server
+            && self.username == other.username
+            && self.password_source == other.password_source
+            && self.ca_cert == other.ca_cert
+    }
 }
 
 impl Default for Config {
@@ -81,16 +189,28 @@ impl Default for Config {
 
         Self {
             unique_id: None,
+            configuration_url: None,
+            suggested_area: None,
             mqtt_server,
             username: None,
             password_source: PasswordSource::Keyring,
             update_interval: Duration::from_secs(30),
             discovery_interval: Some(Duration::from_secs(60 * 60)),
+            state_topic_mode: StateTopicMode::default(),
+            publish: PublishConfig::default(),
+            entities: HashMap::new(),
+            homeassistant_status_topic: None,
+            power: PowerConfig::default(),
+            actions: vec![],
             drives: vec![DriveConfig {
                 path: PathBuf::from("/"),
                 name: String::from("root"),
             }],
             ca_cert: None,
+            state_dir: None,
+            offline_queue: None,
+            collectors: CollectorsConfig::default(),
+            lm_sensors: LmSensorsConfig::default(),
         }
     }
 }
@@ -104,11 +224,235 @@ pub struct DriveConfig {
     pub name: String,
 }
 
-/// Source of the MQTT password.
+/// How entity states are laid out over MQTT topics.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StateTopicMode {
+    /// Publish all entities as one JSON object on `system-mqtt/<id>/state`.
+    #[serde(rename = "combined")]
+    #[default]
+    Combined,
+
+    /// Publish each entity as a plain value on its own `system-mqtt/<id>/<entity>` topic.
+    #[serde(rename = "per_entity")]
+    PerEntity,
+}
+
+/// Configuration for the on-disk queue of state messages kept while disconnected.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct OfflineQueueConfig {
+    /// The largest size of the queue in bytes. The oldest messages are dropped beyond it.
+    /// If not specified, defaults to 1 MiB.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<u64>,
+
+    /// Messages older than this are dropped instead of being published.
+    /// If not specified, defaults to 24 hours.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_age: Option<Duration>,
+
+    /// Add the time each message was produced to it as a `timestamp` field when it is published.
+    /// Only applies to JSON object payloads, such as the combined state message.
+    #[serde(default)]
+    pub include_timestamps: bool,
+}
+
+/// Configuration for publish-on-change.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct PublishConfig {
+    /// The minimum change a value must make before it is published again.
+    /// Keys are either entity IDs or device classes (e.g. `temperature`), with entity IDs
+    /// taking precedence. Entities without a deadband are published every time they are collected
+    /// in per-entity mode, and every time they change in combined mode.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub deadbands: HashMap<String, f64>,
+
+    /// The longest time a value held back by its deadband may go without being published.
+    /// If not specified, defaults to 10 minutes.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub heartbeat: Option<Duration>,
+}
+
+impl PublishConfig {
+    /// Find the deadband for an entity, by its ID first and its device class second.
+    pub fn deadband_for(&self, entity_id: &str, device_class: Option<&str>) -> Option<f64> {
+        self.deadbands
+            .get(entity_id)
+            .or_else(|| device_class.and_then(|device_class| self.deadbands.get(device_class)))
+            .copied()
+    }
+}
+
+/// Overrides for the metadata of a single entity.
+///
+/// Every field that is not specified keeps the value chosen by the collector.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct EntityConfig {
+    /// The friendly name shown in Home Assistant.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    /// The Material Design Icons name of the entity, such as `mdi:thermometer`.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+
+    /// The Home Assistant device class, such as `temperature`.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_class: Option<String>,
+
+    /// The Home Assistant state class, such as `measurement`.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_class: Option<String>,
+
+    /// The unit of measurement, such as `°C`.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+
+    /// The Home Assistant entity category, either `config` or `diagnostic`.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entity_category: Option<String>,
+
+    /// Leave the entity out entirely. Its values are neither registered nor published.
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+/// Configuration for the power management buttons.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct PowerConfig {
+    /// Reboot the device. Runs `systemctl reboot` by default.
+    #[serde(default)]
+    pub reboot: PowerActionConfig,
+
+    /// Shut the device down. Runs `systemctl poweroff` by default.
+    #[serde(default)]
+    pub shutdown: PowerActionConfig,
+
+    /// Suspend the device. Runs `systemctl suspend` by default.
+    #[serde(default)]
+    pub suspend: PowerActionConfig,
+
+    /// Hibernate the device. Runs `systemctl hibernate` by default.
+    #[serde(default)]
+    pub hibernate: PowerActionConfig,
+
+    /// Lock all sessions on the device. Runs `loginctl lock-sessions` by default.
+    #[serde(default)]
+    pub lock: PowerActionConfig,
+}
+
+/// Configuration for a single power management button.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct PowerActionConfig {
+    /// Whether the button is registered with Home Assistant.
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// The program and arguments to run instead of the default command.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<Vec<String>>,
+}
+
+/// Configuration for a command that can be run from Home Assistant.
 #[derive(Serialize, Deserialize, Clone)]
+pub struct ActionConfig {
+    /// The name of the button, also used as its entity ID, so it must be a valid one.
+    pub name: String,
+
+    /// The program to run.
+    pub command: String,
+
+    /// The arguments passed to the program.
+    #[serde(default)]
+    pub args: Vec<String>,
+
+    /// How long the command may run before it is killed.
+    /// If not specified, defaults to 60 seconds.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<Duration>,
+
+    /// The user to run the command as.
+    /// If not specified, the command runs as the user running the daemon.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+
+    /// The icon of the button.
+    /// If not specified, defaults to `mdi:script-text-play`.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+}
+
+/// Configuration for the individual statistics collectors.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct CollectorsConfig {
+    /// Uptime, CPU, memory and swap usage.
+    #[serde(default)]
+    pub system: CollectorConfig,
+
+    /// Battery state and charge level.
+    #[serde(default)]
+    pub battery: CollectorConfig,
+
+    /// Filesystem usage of the configured drives.
+    #[serde(default)]
+    pub drives: CollectorConfig,
+
+    /// Hardware sensors reported by lm-sensors.
+    #[serde(default)]
+    pub lm_sensors: CollectorConfig,
+
+    /// NVIDIA GPU statistics reported by `nvidia-smi`.
+    #[serde(default)]
+    pub nvidia_gpu: CollectorConfig,
+}
+
+/// Configuration shared by all collectors.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct CollectorConfig {
+    /// Whether the collector is enabled.
+    /// If not specified, it is enabled if this build of system-mqtt supports it.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+
+    /// The interval at which this collector is sampled and its values published.
+    /// If not specified, the global `update_interval` is used.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub update_interval: Option<Duration>,
+}
+
+impl CollectorConfig {
+    /// Whether the collector is enabled, which it is unless turned off.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.unwrap_or(true)
+    }
+
+    /// The interval at which this collector should run, falling back to the global one.
+    pub fn update_interval(&self, config: &Config) -> Duration {
+        self.update_interval.unwrap_or(config.update_interval)
+    }
+}
+
+/// Source of the MQTT password.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Default)]
 pub enum PasswordSource {
     /// Use the system keyring to store and retrieve the password.
     #[serde(rename = "keyring")]
+    #[default]
     Keyring,
 
     /// Read the password from a file.
@@ -122,8 +466,82 @@ pub enum PasswordSource {
     Plaintext(String),
 }
 
-impl Default for PasswordSource {
-    fn default() -> Self {
-        Self::Keyring
+/// Settings for the lm-sensors collector.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct LmSensorsConfig {
+    /// Where the sensor readings come from.
+    #[serde(default)]
+    pub backend: SensorsBackend,
+
+    /// The root of the sysfs tree read by the `sysfs` backend.
+    /// If not specified, `/sys` is used.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sysfs_root: Option<PathBuf>,
+
+    /// Only sensors matching at least one of these rules are reported.
+    /// If empty, every sensor is reported unless excluded.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<SensorFilterRule>,
+
+    /// Sensors matching any of these rules are never reported.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<SensorFilterRule>,
+}
+
+impl LmSensorsConfig {
+    /// The root of the sysfs tree read by the `sysfs` backend.
+    pub fn sysfs_root(&self) -> PathBuf {
+        self.sysfs_root
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("/sys"))
     }
 }
+
+/// Where hardware sensor readings come from.
+///
+/// Defaults to libsensors, unless system-mqtt was built without the `lm-sensors` feature.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SensorsBackend {
+    /// Read the sensors through the libsensors library, applying its configuration.
+    #[serde(rename = "libsensors")]
+    #[cfg_attr(feature = "lm-sensors", default)]
+    Libsensors,
+
+    /// Read the sensors straight from `/sys/class/hwmon` and `/sys/class/thermal`, without
+    /// needing libsensors.
+    #[serde(rename = "sysfs")]
+    #[cfg_attr(not(feature = "lm-sensors"), default)]
+    Sysfs,
+}
+
+/// The error for an option that needs a cargo feature this build was compiled without.
+pub fn missing_feature(option: &str, feature: &str) -> anyhow::Error {
+    anyhow::anyhow!(
+        "`{}` is not supported, because system-mqtt was built without the `{}` feature.",
+        option, feature
+    )
+}
+
+/// A rule matching sensors by glob patterns, such as `nct6775-*`.
+///
+/// A sensor matches the rule if it matches every pattern that is specified.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SensorFilterRule {
+    /// A pattern for the name of the chip, such as `coretemp-isa-0000`.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chip: Option<String>,
+
+    /// A pattern for the label of the feature, such as `Package id 0`.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub feature: Option<String>,
+
+    /// A pattern for the kind of the feature, such as `fan` or `voltage`.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+}
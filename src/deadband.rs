@@ -0,0 +1,174 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Decides whether a value has changed enough since it was last published to be worth
+/// publishing again.
+///
+/// Values of entities without a deadband are always due. Values of entities with one are due
+/// once they move past it, or once the heartbeat has elapsed since they were last published.
+pub struct DeadbandFilter {
+    heartbeat: Duration,
+    deadbands: HashMap<String, f64>,
+    last_published: HashMap<String, (Value, Instant)>,
+    /// When a batch of values was last published as a whole.
+    last_batch: Option<Instant>,
+}
+
+impl DeadbandFilter {
+    pub fn new(heartbeat: Duration) -> Self {
+        Self {
+            heartbeat,
+            deadbands: HashMap::new(),
+            last_published: HashMap::new(),
+            last_batch: None,
+        }
+    }
+
+    /// Set the deadband for an entity.
+    pub fn set_deadband(&mut self, entity_id: &str, deadband: f64) {
+        self.deadbands.insert(entity_id.to_string(), deadband);
+    }
+
+    /// Check whether `value` should be published for the given entity.
+    pub fn is_due(&self, entity_id: &str, value: &Value, now: Instant) -> bool {
+        let Some(deadband) = self.deadbands.get(entity_id) else {
+            return true;
+        };
+        let Some((last_value, last_time)) = self.last_published.get(entity_id) else {
+            return true;
+        };
+
+        if now.duration_since(*last_time) >= self.heartbeat {
+            return true;
+        }
+
+        match (value.as_f64(), last_value.as_f64()) {
+            (Some(value), Some(last_value)) => (value - last_value).abs() > *deadband,
+            _ => value != last_value,
+        }
+    }
+
+    /// Check whether a batch of values should be published as a whole, the way the combined
+    /// state is.
+    ///
+    /// A batch is due once a value with a deadband moves past it, or a value without one
+    /// changes at all, so that changes such as an alarm going off are never held back. JSON
+    /// attributes, whose key ends in `_attributes`, only ride along. Without any deadband every
+    /// batch is due. Otherwise batches are still published once the heartbeat has elapsed since
+    /// the last one was.
+    pub fn is_batch_due(&self, values: &HashMap<String, Value>, now: Instant) -> bool {
+        if self.deadbands.is_empty() {
+            return true;
+        }
+        if self.last_batch.is_none_or(|last_batch| now.duration_since(last_batch) >= self.heartbeat) {
+            return true;
+        }
+
+        values.iter().any(|(entity_id, value)| {
+            if self.deadbands.contains_key(entity_id) {
+                self.is_due(entity_id, value, now)
+            } else if entity_id.ends_with("_attributes") {
+                false
+            } else {
+                self.last_published
+                    .get(entity_id)
+                    .is_none_or(|(last_value, _)| last_value != value)
+            }
+        })
+    }
+
+    /// Record that a batch of values has been published as a whole.
+    ///
+    /// Unlike [`Self::mark_published`], this also remembers the values without a deadband, so
+    /// that the next batch can tell whether they changed.
+    pub fn mark_batch_published(&mut self, values: &HashMap<String, Value>, now: Instant) {
+        for (entity_id, value) in values {
+            self.last_published.insert(entity_id.clone(), (value.clone(), now));
+        }
+        self.last_batch = Some(now);
+    }
+
+    /// Record that `value` has been published for the given entity.
+    pub fn mark_published(&mut self, entity_id: &str, value: &Value, now: Instant) {
+        if self.deadbands.contains_key(entity_id) {
+            self.last_published.insert(entity_id.to_string(), (value.clone(), now));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch(values: &[(&str, Value)]) -> HashMap<String, Value> {
+        values
+            .iter()
+            .map(|(entity_id, value)| (entity_id.to_string(), value.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn batch_within_deadband_is_held_back() {
+        let mut filter = DeadbandFilter::new(Duration::from_secs(600));
+        filter.set_deadband("cpu", 1.0);
+        let start = Instant::now();
+
+        let first = batch(&[
+            ("cpu", Value::from(10.0)),
+            ("cpu_alarm", Value::from("OFF")),
+            ("cpu_attributes", serde_json::json!({ "max": 90.0 })),
+        ]);
+        assert!(filter.is_batch_due(&first, start));
+        filter.mark_batch_published(&first, start);
+
+        // Neither unchanged values without a deadband nor attributes make the batch due.
+        let second = batch(&[
+            ("cpu", Value::from(10.5)),
+            ("cpu_alarm", Value::from("OFF")),
+            ("cpu_attributes", serde_json::json!({ "max": 100.0 })),
+        ]);
+        assert!(!filter.is_batch_due(&second, start + Duration::from_secs(30)));
+
+        let third = batch(&[("cpu", Value::from(11.5)), ("cpu_alarm", Value::from("OFF"))]);
+        assert!(filter.is_batch_due(&third, start + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn batch_with_changed_value_without_deadband_is_due() {
+        let mut filter = DeadbandFilter::new(Duration::from_secs(600));
+        filter.set_deadband("temperature", 0.5);
+        let start = Instant::now();
+
+        let first = batch(&[("temperature", Value::from(40.0)), ("cpu_alarm", Value::from("OFF"))]);
+        filter.mark_batch_published(&first, start);
+
+        let second = batch(&[("temperature", Value::from(40.2)), ("cpu_alarm", Value::from("ON"))]);
+        assert!(filter.is_batch_due(&second, start + Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn batch_is_due_after_heartbeat() {
+        let mut filter = DeadbandFilter::new(Duration::from_secs(600));
+        filter.set_deadband("cpu", 1.0);
+        let start = Instant::now();
+
+        let values = batch(&[("cpu", Value::from(10.0))]);
+        filter.mark_batch_published(&values, start);
+
+        assert!(!filter.is_batch_due(&values, start + Duration::from_secs(599)));
+        assert!(filter.is_batch_due(&values, start + Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn every_batch_is_due_without_deadbands() {
+        let mut filter = DeadbandFilter::new(Duration::from_secs(600));
+        let start = Instant::now();
+
+        let values = batch(&[("uptime", Value::from(100))]);
+        filter.mark_batch_published(&values, start);
+
+        assert!(filter.is_batch_due(&values, start + Duration::from_secs(30)));
+    }
+}
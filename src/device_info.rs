@@ -0,0 +1,118 @@
+use std::fs;
+use std::path::Path;
+use anyhow::{Context, Result};
+use ring::hmac;
+use sysinfo::System;
+use crate::config::Config;
+use crate::discovery::Device;
+
+/// Values firmware vendors leave in DMI fields they did not fill in.
+const DMI_PLACEHOLDERS: &[&str] = &[
+    "To Be Filled By O.E.M.",
+    "To be filled by O.E.M.",
+    "Default string",
+    "System Product Name",
+    "System manufacturer",
+    "System Version",
+    "Not Specified",
+    "Not Applicable",
+    "None",
+];
+
+/// The key the machine ID is hashed with, so that the device ID cannot be traced back to it.
+const MACHINE_ID_KEY: &[u8] = b"system-mqtt device id";
+
+/// The ID of this machine when the configuration does not set one.
+///
+/// It is derived from the machine ID, so that it survives renaming the host. The machine ID is
+/// meant to stay confidential, so only a keyed hash of it is used. Systems without a machine
+/// ID fall back to the hostname.
+pub fn default_device_id() -> Result<String> {
+    let machine_id = fs::read_to_string("/etc/machine-id")
+        .or_else(|_| fs::read_to_string("/var/lib/dbus/machine-id"));
+
+    match machine_id.as_deref().map(str::trim) {
+        Ok(machine_id) if !machine_id.is_empty() => {
+            let key = hmac::Key::new(hmac::HMAC_SHA256, MACHINE_ID_KEY);
+            let tag = hmac::sign(&key, machine_id.as_bytes());
+            Ok(tag.as_ref()[..8].iter().map(|byte| format!("{:02x}", byte)).collect())
+        }
+        _ => {
+            log::warn!("Could not read the machine ID, falling back to the hostname as the device ID.");
+            hostname()
+        }
+    }
+}
+
+/// The hostname of this machine, which Home Assistant shows as the name of the device.
+pub fn hostname() -> Result<String> {
+    System::host_name().context("Could not get system hostname.")
+}
+
+/// Describe this machine to Home Assistant.
+///
+/// The device is named after the hostname, falling back to its ID. The manufacturer, model and
+/// hardware version come from DMI, the software version from the OS release, and the
+/// connections from the MAC addresses of the physical network interfaces. Any of them that
+/// cannot be read is left out.
+pub fn device(device_id: &str, config: &Config) -> Device {
+    Device {
+        identifiers: vec![device_id.to_string()],
+        name: hostname().unwrap_or_else(|_| device_id.to_string()),
+        manufacturer: read_dmi("sys_vendor"),
+        model: read_dmi("product_name"),
+        hw_version: read_dmi("product_version"),
+        sw_version: os_release(),
+        connections: mac_addresses()
+            .into_iter()
+            .map(|address| ("mac".to_string(), address))
+            .collect(),
+        configuration_url: config.configuration_url.as_ref().map(ToString::to_string),
+        suggested_area: config.suggested_area.clone(),
+    }
+}
+
+fn read_dmi(field: &str) -> Option<String> {
+    let value = fs::read_to_string(Path::new("/sys/class/dmi/id").join(field)).ok()?;
+    let value = value.trim();
+
+    if value.is_empty() || DMI_PLACEHOLDERS.contains(&value) {
+        None
+    } else {
+        Some(value.to_string())
+    }
+}
+
+/// The pretty name of the operating system, such as `Debian GNU/Linux 12 (bookworm)`.
+fn os_release() -> Option<String> {
+    let contents = fs::read_to_string("/etc/os-release")
+        .or_else(|_| fs::read_to_string("/usr/lib/os-release"))
+        .ok()?;
+
+    contents.lines().find_map(|line| {
+        let value = line.strip_prefix("PRETTY_NAME=")?;
+        Some(value.trim_matches(|c| c == '"' || c == '\'').to_string())
+    })
+}
+
+/// The MAC addresses of the physical network interfaces, in a stable order.
+///
+/// Virtual interfaces such as bridges, VPN tunnels and container veths are skipped, since
+/// they come and go and would keep changing the device in Home Assistant.
+fn mac_addresses() -> Vec<String> {
+    let Ok(interfaces) = fs::read_dir("/sys/class/net") else {
+        return vec![];
+    };
+
+    let mut addresses: Vec<String> = interfaces
+        .flatten()
+        .filter(|interface| interface.path().join("device").exists())
+        .filter_map(|interface| fs::read_to_string(interface.path().join("address")).ok())
+        .map(|address| address.trim().to_lowercase())
+        .filter(|address| !address.is_empty() && address != "00:00:00:00:00:00")
+        .collect();
+
+    addresses.sort();
+    addresses.dedup();
+    addresses
+}
@@ -7,16 +7,89 @@ pub struct SingleComponentDiscoveryPayload {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub device_class: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub state_class: Option<String>,
-    pub state_topic: String,
-    pub value_template: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub json_attributes_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub json_attributes_template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_on: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_off: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub unit_of_measurement: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entity_category: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub availability: Vec<Availability>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub availability_mode: Option<String>,
     pub icon: Option<String>,
     pub device: Device,
+    pub origin: Origin,
 }
 
+/// A topic telling Home Assistant whether an entity is available.
 #[derive(Serialize)]
+pub struct Availability {
+    pub topic: String,
+    pub payload_available: String,
+    pub payload_not_available: String,
+}
+
+impl Availability {
+    /// An availability topic using the `online`/`offline` payloads published by the daemon.
+    pub fn new(topic: String) -> Self {
+        Self {
+            topic,
+            payload_available: "online".to_string(),
+            payload_not_available: "offline".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Clone)]
 pub struct Device {
     pub identifiers: Vec<String>,
     pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub manufacturer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hw_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sw_version: Option<String>,
+    /// Pairs of connection type and identifier, such as `("mac", "00:11:22:33:44:55")`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub connections: Vec<(String, String)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub configuration_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suggested_area: Option<String>,
+}
+
+/// The application that published a discovery payload.
+#[derive(Serialize)]
+pub struct Origin {
+    pub name: &'static str,
+    pub sw_version: &'static str,
+    pub support_url: &'static str,
+}
+
+impl Origin {
+    pub fn system_mqtt() -> Self {
+        Self {
+            name: env!("CARGO_PKG_NAME"),
+            sw_version: env!("CARGO_PKG_VERSION"),
+            support_url: env!("CARGO_PKG_REPOSITORY"),
+        }
+    }
 }
@@ -0,0 +1,82 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use crate::utils::write_atomically;
+
+/// Discovery topics, each with the name of the collector whose entity it announces, if any.
+pub type TopicOwners = HashMap<String, Option<String>>;
+
+/// The saved topics, as a list of topics in versions that did not record their collectors.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SavedTopics {
+    Owners(TopicOwners),
+    Topics(HashSet<String>),
+}
+
+/// The discovery topics published by the last run of the daemon, kept on disk so that
+/// entities which disappeared while the daemon was not running can still be retracted.
+pub struct PublishedTopics {
+    path: PathBuf,
+}
+
+impl PublishedTopics {
+    pub fn new(state_dir: &Path) -> Self {
+        Self {
+            path: state_dir.join("discovery-topics.json"),
+        }
+    }
+
+    /// The topics that were saved last with their collectors, or none if nothing was saved yet.
+    pub async fn load(&self) -> Result<TopicOwners> {
+        let contents = match fs::read_to_string(&self.path).await {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(TopicOwners::new()),
+            Err(error) => return Err(error).context("Failed to read published discovery topics."),
+        };
+
+        let topics = serde_json::from_str(&contents).context("Failed to parse published discovery topics.")?;
+        Ok(match topics {
+            SavedTopics::Owners(owners) => owners,
+            SavedTopics::Topics(topics) => topics.into_iter().map(|topic| (topic, None)).collect(),
+        })
+    }
+
+    /// Replace the saved topics.
+    pub async fn save(&self, topics: &TopicOwners) -> Result<()> {
+        let topics: BTreeMap<_, _> = topics.iter().collect();
+        let contents = serde_json::to_string_pretty(&topics)
+            .context("Failed to serialize published discovery topics.")?;
+
+        write_atomically(&self.path, &contents).await
+    }
+}
+
+/// The device ID used by the last run of the daemon, kept on disk so that the entities of the
+/// old ID can be retracted when it changes.
+pub struct LastDeviceId {
+    path: PathBuf,
+}
+
+impl LastDeviceId {
+    pub fn new(state_dir: &Path) -> Self {
+        Self {
+            path: state_dir.join("device-id"),
+        }
+    }
+
+    /// The device ID that was saved last, or `None` if nothing was saved yet.
+    pub async fn load(&self) -> Result<Option<String>> {
+        match fs::read_to_string(&self.path).await {
+            Ok(contents) => Ok(Some(contents.trim().to_string())),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error).context("Failed to read the last device ID."),
+        }
+    }
+
+    pub async fn save(&self, device_id: &str) -> Result<()> {
+        write_atomically(&self.path, &format!("{}\n", device_id)).await
+    }
+}
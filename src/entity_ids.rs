@@ -0,0 +1,77 @@
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use crate::utils::{slugify, write_atomically};
+
+/// The entity IDs handed out to hardware, kept on disk so that every sensor keeps its entity
+/// across restarts.
+///
+/// Hardware is identified by something that does not change when it is enumerated in another
+/// order, such as the device path of a chip or the UUID of a GPU. The first time a piece of
+/// hardware is seen, it gets an ID made from its name, with a numbered suffix if that ID is
+/// already taken.
+pub struct EntityIds {
+    path: PathBuf,
+    ids: BTreeMap<String, String>,
+    taken: HashSet<String>,
+    changed: bool,
+}
+
+impl EntityIds {
+    /// Load the IDs handed out before. If they cannot be read, IDs are handed out afresh.
+    pub fn load(state_dir: &Path) -> Self {
+        let path = state_dir.join("entity-ids.json");
+        let ids: BTreeMap<String, String> = match std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|error| {
+                log::warn!("Ignoring corrupt entity ID mapping: {:#}", error);
+                BTreeMap::new()
+            }),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(error) => {
+                log::warn!("Could not read entity ID mapping: {:#}", error);
+                BTreeMap::new()
+            }
+        };
+
+        Self {
+            path,
+            taken: ids.values().cloned().collect(),
+            ids,
+            changed: false,
+        }
+    }
+
+    /// The entity ID of a piece of hardware, handing out a new one made from `name` if it has
+    /// none yet.
+    pub fn resolve(&mut self, identity: &str, name: &str) -> String {
+        if let Some(entity_id) = self.ids.get(identity) {
+            return entity_id.clone();
+        }
+
+        let slug = slugify(name);
+        let mut entity_id = slug.clone();
+        let mut suffix = 2;
+        while self.taken.contains(&entity_id) {
+            entity_id = format!("{}_{}", slug, suffix);
+            suffix += 1;
+        }
+
+        log::debug!("Assigning entity ID `{}` to `{}`.", entity_id, identity);
+        self.ids.insert(identity.to_string(), entity_id.clone());
+        self.taken.insert(entity_id.clone());
+        self.changed = true;
+        entity_id
+    }
+
+    /// Save the IDs, if any were handed out since they were loaded or last saved.
+    pub async fn save(&mut self) -> Result<()> {
+        if !self.changed {
+            return Ok(());
+        }
+
+        let contents = serde_json::to_string_pretty(&self.ids).context("Failed to serialize entity IDs.")?;
+        write_atomically(&self.path, &contents).await?;
+        self.changed = false;
+        Ok(())
+    }
+}
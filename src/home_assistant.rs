@@ -1,7 +1,19 @@
-use rumqttc::{AsyncClient, QoS};
-use std::collections::HashSet;
+use rumqttc::{AsyncClient, ClientError, Publish, QoS};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
 use anyhow::{Context, Result, bail};
-use crate::discovery::{Device, SingleComponentDiscoveryPayload};
+use crate::actions::ActionResult;
+use crate::config::{Config, EntityConfig, PublishConfig, StateTopicMode};
+use crate::deadband::DeadbandFilter;
+use crate::collector::CollectorFailures;
+use crate::discovery_state::{LastDeviceId, PublishedTopics, TopicOwners};
+use crate::entity_ids::EntityIds;
+use crate::discovery::{Availability, Device, Origin, SingleComponentDiscoveryPayload};
+use crate::offline_queue::OfflineQueue;
 
 /// Builder for entity registration parameters.
 /// 
@@ -14,6 +26,9 @@ pub struct EntityRegistrationBuilder<'a> {
     entity_id: &'a str,
     unit_of_measurement: Option<&'a str>,
     icon: Option<&'a str>,
+    name: Option<&'a str>,
+    entity_category: Option<&'a str>,
+    json_attributes: bool,
 }
 
 impl<'a> EntityRegistrationBuilder<'a> {
@@ -21,7 +36,8 @@ impl<'a> EntityRegistrationBuilder<'a> {
     /// 
     /// # Arguments
     /// 
-    /// * `platform` - The Home Assistant platform type (e.g., "sensor", "binary_sensor")
+    /// * `platform` - The Home Assistant platform type (e.g., "sensor", "binary_sensor", "button").
+    ///   Buttons get a command topic instead of a state topic.
     /// * `entity_id` - The unique identifier for this entity
     pub fn new(platform: &'a str, entity_id: &'a str) -> Self {
         Self {
@@ -31,6 +47,9 @@ impl<'a> EntityRegistrationBuilder<'a> {
             entity_id,
             unit_of_measurement: None,
             icon: None,
+            name: None,
+            entity_category: None,
+            json_attributes: false,
         }
     }
 
@@ -67,17 +86,76 @@ impl<'a> EntityRegistrationBuilder<'a> {
         self.icon = Some(icon);
         self
     }
+
+    /// Set the entity category for this entity.
+    /// 
+    /// Entities in the "diagnostic" or "config" category are shown apart from the main
+    /// entities of the device.
+    pub fn entity_category(mut self, entity_category: &'a str) -> Self {
+        self.entity_category = Some(entity_category);
+        self
+    }
+
+    /// Give this entity JSON attributes.
+    /// 
+    /// The attributes are published as an object under the `<entity_id>_attributes` key,
+    /// next to the value of the entity.
+    pub fn json_attributes(mut self) -> Self {
+        self.json_attributes = true;
+        self
+    }
+
+    /// Set the friendly name for this entity.
+    /// 
+    /// If not set, the name is made of the device and entity IDs.
+    #[cfg_attr(not(feature = "nvidia"), allow(dead_code))]
+    pub fn name(mut self, name: &'a str) -> Self {
+        self.name = Some(name);
+        self
+    }
+
+    /// Apply the overrides from the configuration, replacing any value set by the collector.
+    pub fn overrides(mut self, overrides: &'a EntityConfig) -> Self {
+        if let Some(name) = &overrides.name {
+            self.name = Some(name);
+        }
+        if let Some(icon) = &overrides.icon {
+            self.icon = Some(icon);
+        }
+        if let Some(device_class) = &overrides.device_class {
+            self.device_class = Some(device_class);
+        }
+        if let Some(state_class) = &overrides.state_class {
+            self.state_class = Some(state_class);
+        }
+        if let Some(unit) = &overrides.unit {
+            self.unit_of_measurement = Some(unit);
+        }
+        if let Some(entity_category) = &overrides.entity_category {
+            self.entity_category = Some(entity_category);
+        }
+        self
+    }
 }
 
+/// Entity IDs that cannot be used, since the per-entity state topic `system-mqtt/<id>/<entity_id>`
+/// would be the availability topic or the combined state topic of the device.
+const RESERVED_ENTITY_IDS: &[&str] = &["availability", "state"];
+
 /// Validates that an entity ID contains only valid characters.
 /// 
 /// Entity IDs should only contain lowercase alphanumeric characters and underscores.
 /// This function ensures the ID follows Home Assistant's naming conventions.
-fn validate_entity_id(entity_id: &str) -> Result<()> {
+/// IDs whose per-entity state topic is used for something else are rejected as well.
+pub fn validate_entity_id(entity_id: &str) -> Result<()> {
     if entity_id.is_empty() {
         bail!("Entity ID cannot be empty");
     }
 
+    if RESERVED_ENTITY_IDS.contains(&entity_id) {
+        bail!("Entity ID '{}' is reserved", entity_id);
+    }
+
     // Check if entity_id contains only lowercase alphanumeric characters and underscores
     if !entity_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
         bail!("Entity ID '{}' contains invalid characters", entity_id);
@@ -86,15 +164,57 @@ fn validate_entity_id(entity_id: &str) -> Result<()> {
     Ok(())
 }
 
+/// How long the retained discovery topics of a retired device ID are listened for after
+/// subscribing to them.
+const RETIRING_WINDOW: Duration = Duration::from_secs(10);
+
+/// The topic filter matching the discovery topics of every entity of a device ID.
+fn retired_discovery_filter(device_id: &str) -> String {
+    format!("homeassistant/+/system-mqtt-{}/+/config", device_id)
+}
+
+/// The topic the online/offline status of a device is published to.
+pub fn availability_topic(device_id: &str) -> String {
+    format!("system-mqtt/{}/availability", device_id)
+}
+
+/// The topic the online/offline status of a single collector is published to.
+fn collector_availability_topic(device_id: &str, collector: &str) -> String {
+    format!("system-mqtt/{}/{}/availability", device_id, collector)
+}
+
 /// Main interface for Home Assistant integration.
 /// 
 /// This struct handles the communication with Home Assistant through MQTT,
 /// including entity registration, state updates, and availability reporting.
 pub struct HomeAssistant {
     client: AsyncClient,
+    connection: watch::Receiver<bool>,
     device_id: String,
+    device: Device,
+    state_topic_mode: StateTopicMode,
+    publish_config: PublishConfig,
+    entity_overrides: HashMap<String, EntityConfig>,
+    deadband_filter: DeadbandFilter,
     registered_topics: HashSet<String>,
-    discovery_info: Vec<(String, SingleComponentDiscoveryPayload)>
+    command_topics: HashMap<String, String>,
+    discovery_info: Vec<(String, SingleComponentDiscoveryPayload)>,
+    /// The collectors that registered the entities, by discovery topic.
+    discovery_collectors: HashMap<String, String>,
+    published_topics: PublishedTopics,
+    last_device_id: LastDeviceId,
+    entity_ids: EntityIds,
+    stale_topics: HashSet<String>,
+    /// The device ID of an earlier run whose entities are still to be retracted.
+    retired_device_id: Option<String>,
+    /// When to stop listening for the entities of the retired device ID, once subscribed.
+    retiring_until: Option<Instant>,
+    state: HashMap<String, Value>,
+    registering_collector: Option<String>,
+    collector_availability: HashMap<String, bool>,
+    connected: bool,
+    offline_queue: Option<OfflineQueue>,
+    status_topic: String,
 }
 
 impl HomeAssistant {
@@ -104,13 +224,45 @@ impl HomeAssistant {
     /// 
     /// * `device_id` - The unique identifier for this device
     /// * `client` - The MQTT client to use for communication
-    pub fn new(device_id: String, client: AsyncClient) -> Result<Self> {
-        let home_assistant = Self {
+    /// * `connection` - Whether the MQTT event loop is connected to the broker
+    /// * `config` - The configuration deciding how states are published
+    pub fn new(
+        device_id: String,
+        client: AsyncClient,
+        connection: watch::Receiver<bool>,
+        config: &Config,
+    ) -> Result<Self> {
+        let heartbeat = config.publish.heartbeat.unwrap_or(Duration::from_secs(10 * 60));
+        // The `available` sensor of earlier versions never had a value and was replaced by the
+        // connectivity binary sensor.
+        let legacy_available_topic = format!("homeassistant/sensor/system-mqtt-{}/available/config", device_id);
+        let mut home_assistant = Self {
             client,
+            connection,
+            device: crate::device_info::device(&device_id, config),
             device_id,
+            state_topic_mode: config.state_topic_mode,
+            publish_config: config.publish.clone(),
+            entity_overrides: config.entities.clone(),
+            deadband_filter: DeadbandFilter::new(heartbeat),
             registered_topics: HashSet::new(),
+            command_topics: HashMap::new(),
             discovery_info: vec![],
+            discovery_collectors: HashMap::new(),
+            published_topics: PublishedTopics::new(&config.state_dir()),
+            last_device_id: LastDeviceId::new(&config.state_dir()),
+            entity_ids: EntityIds::load(&config.state_dir()),
+            stale_topics: HashSet::from([legacy_available_topic]),
+            retired_device_id: None,
+            retiring_until: None,
+            state: HashMap::new(),
+            registering_collector: None,
+            collector_availability: HashMap::new(),
+            connected: false,
+            offline_queue: build_offline_queue(config),
+            status_topic: status_topic(config),
         };
+        home_assistant.register_connectivity();
 
         Ok(home_assistant)
     }
@@ -124,15 +276,216 @@ impl HomeAssistant {
     /// * `available` - Whether the device is available (true) or unavailable (false)
     pub async fn set_available(&self, available: bool) -> Result<()> {
         let payload = if available { "online" } else { "offline" };
-        self.client
-            .publish(
-                format!("system-mqtt/{}/availability", self.device_id),
-                QoS::AtLeastOnce,
-                true,
-                payload,
-            )
+        self.request(self.client.publish(
+            availability_topic(&self.device_id),
+            QoS::AtLeastOnce,
+            true,
+            payload,
+        ))
+        .await
+        .context("Failed to publish availability topic.")?;
+
+        Ok(())
+    }
+
+    /// Set the availability state of a single collector.
+    ///
+    /// The entities of the collector go unavailable in Home Assistant while it is unavailable,
+    /// independently of the rest of the device.
+    pub async fn set_collector_available(&mut self, collector: &str, available: bool) -> Result<()> {
+        if available {
+            log::info!("Collector `{}` is available.", collector);
+        } else {
+            log::warn!("Collector `{}` is unavailable.", collector);
+        }
+
+        self.collector_availability.insert(collector.to_string(), available);
+        if self.connected {
+            self.publish_collector_availability(collector, available).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn publish_collector_availability(&self, collector: &str, available: bool) -> Result<()> {
+        let payload = if available { "online" } else { "offline" };
+        self.request(self.client.publish(
+            collector_availability_topic(&self.device_id, collector),
+            QoS::AtLeastOnce,
+            true,
+            payload,
+        ))
+        .await
+        .context("Failed to publish collector availability topic.")?;
+
+        Ok(())
+    }
+
+    /// Publish the availability of the device and of every collector.
+    async fn publish_availability(&self) -> Result<()> {
+        self.set_available(true).await?;
+        for (collector, available) in &self.collector_availability {
+            self.publish_collector_availability(collector, *available).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether the MQTT client is currently connected to the broker.
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// Track the connection state of the MQTT client.
+    ///
+    /// While disconnected, state messages are put in the offline queue if there is one, and
+    /// dropped otherwise. Once connected, the Home Assistant status topic is subscribed to and
+    /// the device is announced, since the broker may have lost it in the meantime. Messages
+    /// queued while disconnected are published before the latest state.
+    pub async fn set_connected(&mut self, connected: bool) -> Result<()> {
+        self.connected = connected;
+
+        if connected {
+            self.subscribe().await?;
+            self.publish_availability().await?;
+            self.publish_discovery().await?;
+            self.retract_stale_topics().await?;
+            self.replay_offline_queue().await?;
+            self.republish_state().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Subscribe to the topics the device listens on.
+    ///
+    /// While the entities of a retired device ID are still to be retracted, their discovery
+    /// topics are subscribed to as well, so that the broker hands over every retained one.
+    pub async fn subscribe(&mut self) -> Result<()> {
+        self.request(self.client.subscribe(self.status_topic.clone(), QoS::AtLeastOnce))
+            .await
+            .context("Failed to subscribe to Home Assistant status topic.")?;
+
+        for command_topic in self.command_topics.keys() {
+            self.request(self.client.subscribe(command_topic.clone(), QoS::AtLeastOnce))
+                .await
+                .context("Failed to subscribe to command topic.")?;
+        }
+
+        if let Some(retired_device_id) = &self.retired_device_id {
+            let sent = self
+                .request(self.client.subscribe(retired_discovery_filter(retired_device_id), QoS::AtLeastOnce))
+                .await
+                .context("Failed to subscribe to the entities of the old device ID.")?;
+            if sent {
+                self.retiring_until = Some(Instant::now() + RETIRING_WINDOW);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// When to stop retracting the entities of the retired device ID, if they are being retracted.
+    pub fn retiring_until(&self) -> Option<Instant> {
+        self.retiring_until
+    }
+
+    /// Retract an entity of the retired device ID if the message is its retained discovery
+    /// information.
+    pub async fn retract_retired_entity(&self, message: &Publish) -> Result<()> {
+        let Some(retired_device_id) = &self.retired_device_id else {
+            return Ok(());
+        };
+        let device = format!("system-mqtt-{}", retired_device_id);
+        let levels: Vec<_> = message.topic.split('/').collect();
+        let is_retired_entity = matches!(
+            levels[..],
+            ["homeassistant", _, topic_device, _, "config"] if topic_device == device
+        );
+        if !is_retired_entity || !message.retain || message.payload.is_empty() {
+            return Ok(());
+        }
+
+        self.retract_discovery([&message.topic]).await?;
+        Ok(())
+    }
+
+    /// Stop listening for the entities of the retired device ID, and remember the current one
+    /// once they have all been retracted.
+    ///
+    /// If the connection was lost in the meantime, they are listened for again once it is back.
+    pub async fn finish_retiring(&mut self) -> Result<()> {
+        self.retiring_until = None;
+        let Some(retired_device_id) = &self.retired_device_id else {
+            return Ok(());
+        };
+
+        let sent = self
+            .request(self.client.unsubscribe(retired_discovery_filter(retired_device_id)))
             .await
-            .context("Failed to publish availability topic.")
+            .context("Failed to unsubscribe from the entities of the old device ID.")?;
+        if sent {
+            log::info!("Retracted the entities of the old device ID `{}`.", retired_device_id);
+            self.retired_device_id = None;
+            self.save_device_id().await;
+        }
+
+        Ok(())
+    }
+
+    /// Announce the device by publishing its availability, discovery information and latest state.
+    pub async fn announce(&mut self) -> Result<()> {
+        self.publish_availability().await?;
+        self.publish_discovery().await?;
+        self.republish_state().await
+    }
+
+    /// The ID of the button entity a message presses, if any.
+    ///
+    /// Only a live `PRESS` counts. A retained message on a command topic was left behind by
+    /// someone else and would run the action again on every reconnect.
+    pub fn pressed_button(&self, message: &Publish) -> Option<&str> {
+        if message.retain || message.payload.as_ref() != b"PRESS" {
+            return None;
+        }
+
+        self.command_topics.get(&message.topic).map(String::as_str)
+    }
+
+    /// Whether a message is Home Assistant announcing that it came online.
+    pub fn is_birth_message(&self, message: &Publish) -> bool {
+        message.topic == self.status_topic && message.payload.as_ref() == b"online"
+    }
+
+    /// Publish the messages queued while disconnected, in the order they were produced.
+    async fn replay_offline_queue(&self) -> Result<()> {
+        let Some(offline_queue) = &self.offline_queue else {
+            return Ok(());
+        };
+
+        let messages = offline_queue.drain().await?;
+        if !messages.is_empty() {
+            log::info!("Publishing {} messages from the offline queue.", messages.len());
+        }
+
+        let mut messages = messages.into_iter();
+        while let Some(message) = messages.next() {
+            let publish = self.client.publish(&message.topic, QoS::AtLeastOnce, false, message.payload.as_str());
+            let sent = self
+                .request(publish)
+                .await
+                .context("Failed to publish queued message.")?;
+
+            // The messages that could not be published are queued again for the next connection.
+            if !sent {
+                for message in std::iter::once(message).chain(messages) {
+                    offline_queue.push(&message.topic, &message.payload).await?;
+                }
+                break;
+            }
+        }
+
+        Ok(())
     }
 
     /// Register an entity using the builder pattern.
@@ -151,21 +504,87 @@ impl HomeAssistant {
         // Validate the entity ID before proceeding
         validate_entity_id(builder.entity_id)?;
 
+        let overrides = self.entity_overrides.get(builder.entity_id).cloned().unwrap_or_default();
+        if overrides.disabled {
+            log::info!("Skipping disabled entity `{}`.", builder.entity_id);
+            return Ok(());
+        }
+        let builder = builder.overrides(&overrides);
+
+        // Entities share the unique ID `<id>-<entity_id>` regardless of their platform, so Home
+        // Assistant would mix up two entities with the same ID.
+        let unique_id = format!("{}-{}", self.device_id, builder.entity_id);
+        if self.discovery_info.iter().any(|(_, payload)| payload.unique_id == unique_id) {
+            bail!("Entity ID '{}' is already used by another entity", builder.entity_id);
+        }
+
         log::info!("Registering entity `{}`.", builder.entity_id);
 
-        let topic = format!("system-mqtt/{}/state", self.device_id);
+        // Buttons have no state. Instead, Home Assistant publishes to their command topic
+        // whenever they are pressed.
+        let (state_topic, value_template, command_topic) = if builder.platform == "button" {
+            (
+                None,
+                None,
+                Some(format!("system-mqtt/{}/{}/command", self.device_id, builder.entity_id)),
+            )
+        } else {
+            match self.state_topic_mode {
+                StateTopicMode::Combined => (
+                    Some(format!("system-mqtt/{}/state", self.device_id)),
+                    Some(format!(r"{{{{ value_json['{entity_id}'] }}}}", entity_id = builder.entity_id)),
+                    None,
+                ),
+                StateTopicMode::PerEntity => (
+                    Some(format!("system-mqtt/{}/{}", self.device_id, builder.entity_id)),
+                    None,
+                    None,
+                ),
+            }
+        };
+        let (json_attributes_topic, json_attributes_template) = if builder.json_attributes {
+            let attributes_id = attributes_id(builder.entity_id);
+            match self.state_topic_mode {
+                StateTopicMode::Combined => (
+                    Some(format!("system-mqtt/{}/state", self.device_id)),
+                    Some(format!(r"{{{{ value_json['{attributes_id}'] | tojson }}}}")),
+                ),
+                StateTopicMode::PerEntity => (
+                    Some(format!("system-mqtt/{}/{}", self.device_id, attributes_id)),
+                    None,
+                ),
+            }
+        } else {
+            (None, None)
+        };
+
+        // Entities of a collector are only available while both the device and the collector are.
+        let mut availability = vec![Availability::new(availability_topic(&self.device_id))];
+        if let Some(collector) = &self.registering_collector {
+            availability.push(Availability::new(collector_availability_topic(&self.device_id, collector)));
+        }
+
         let payload = SingleComponentDiscoveryPayload {
-            unique_id: format!("{}-{}", self.device_id, builder.entity_id),
-            device: Device {
-                identifiers: vec![self.device_id.clone()],
-                name: self.device_id.clone(),
-            },
-            name: format!("{}-{}", self.device_id, builder.entity_id),
+            unique_id,
+            device: self.device.clone(),
+            origin: Origin::system_mqtt(),
+            name: builder
+                .name
+                .map(str::to_string)
+                .unwrap_or_else(|| format!("{}-{}", self.device.name, builder.entity_id)),
             device_class: builder.device_class.map(str::to_string),
             state_class: builder.state_class.map(str::to_string),
-            state_topic: topic.clone(),
-            value_template: format!(r"{{{{ value_json['{entity_id}'] }}}}", entity_id = builder.entity_id),
+            state_topic: state_topic.clone(),
+            value_template,
+            command_topic: command_topic.clone(),
+            json_attributes_topic: json_attributes_topic.clone(),
+            json_attributes_template,
+            payload_on: None,
+            payload_off: None,
             unit_of_measurement: builder.unit_of_measurement.map(str::to_string),
+            entity_category: builder.entity_category.map(str::to_string),
+            availability_mode: (availability.len() > 1).then(|| "all".to_string()),
+            availability,
             icon: builder.icon.map(str::to_string),
         };
 
@@ -173,31 +592,359 @@ impl HomeAssistant {
             "homeassistant/{}/system-mqtt-{}/{}/config",
             builder.platform, self.device_id, builder.entity_id
         );
+        if let Some(deadband) = self.publish_config.deadband_for(builder.entity_id, builder.device_class) {
+            self.deadband_filter.set_deadband(builder.entity_id, deadband);
+        }
+
+        if let Some(collector) = &self.registering_collector {
+            self.discovery_collectors.insert(discovery_topic.clone(), collector.clone());
+        }
         self.discovery_info.push((discovery_topic.clone(), payload));
-        self.registered_topics.insert(topic);
+        if let Some(state_topic) = state_topic {
+            self.registered_topics.insert(state_topic);
+        }
+        if let Some(json_attributes_topic) = json_attributes_topic {
+            self.registered_topics.insert(json_attributes_topic);
+        }
+        if let Some(command_topic) = command_topic {
+            self.command_topics.insert(command_topic, builder.entity_id.to_string());
+            // The outcome of running the action behind the button is published here.
+            self.registered_topics
+                .insert(format!("system-mqtt/{}/{}/result", self.device_id, builder.entity_id));
+        }
         Ok(())
     }
 
+    /// The entity ID of a piece of hardware, handing out a new one made from `name` the first
+    /// time `identity` is seen.
+    pub fn entity_id(&mut self, identity: &str, name: &str) -> String {
+        self.entity_ids.resolve(identity, name)
+    }
+
+    /// Save the entity IDs handed out to hardware, so that it keeps them after a restart.
+    pub async fn save_entity_ids(&mut self) {
+        if let Err(error) = self.entity_ids.save().await {
+            log::warn!("Could not save entity IDs: {:#}", error);
+        }
+    }
+
+    /// Attach the entities registered from now on to the availability of a collector, or to
+    /// the availability of the device only if `None`.
+    pub fn set_registering_collector(&mut self, collector: Option<&str>) {
+        self.registering_collector = collector.map(str::to_string);
+    }
+
+    /// Register the binary sensor reporting whether the device is connected.
+    ///
+    /// Its state is the availability topic itself. Unlike every other entity, it does not
+    /// reference the availability topic, so that it turns off instead of becoming unavailable
+    /// when the device goes offline.
+    fn register_connectivity(&mut self) {
+        let payload = SingleComponentDiscoveryPayload {
+            unique_id: format!("{}-connectivity", self.device_id),
+            device: self.device.clone(),
+            origin: Origin::system_mqtt(),
+            name: format!("{}-connectivity", self.device.name),
+            device_class: Some("connectivity".to_string()),
+            state_class: None,
+            state_topic: Some(availability_topic(&self.device_id)),
+            value_template: None,
+            command_topic: None,
+            json_attributes_topic: None,
+            json_attributes_template: None,
+            payload_on: Some("online".to_string()),
+            payload_off: Some("offline".to_string()),
+            unit_of_measurement: None,
+            entity_category: None,
+            availability: vec![],
+            availability_mode: None,
+            icon: Some("mdi:check-network-outline".to_string()),
+        };
+
+        let discovery_topic = format!(
+            "homeassistant/binary_sensor/system-mqtt-{}/connectivity/config",
+            self.device_id
+        );
+        self.discovery_info.push((discovery_topic, payload));
+    }
+
+    /// Retract the entities that were published before but are no longer registered, and
+    /// save the registered ones so that the next run can do the same.
+    ///
+    /// Besides `previous_topics`, the topics saved by earlier runs are considered, as well as
+    /// the entities of the device ID used by the last run if it changed. Entities are
+    /// retracted right away while connected, and otherwise once the connection is established.
+    ///
+    /// The entities of collectors in `failures`, including those whose hardware is missing, are
+    /// kept and saved again, since a collector that could not be started tells nothing about
+    /// which of its entities still exist.
+    pub async fn retract_stale_discovery(
+        &mut self,
+        mut previous_topics: TopicOwners,
+        failures: &CollectorFailures,
+    ) -> Result<()> {
+        match self.published_topics.load().await {
+            Ok(topics) => {
+                for (topic, collector) in topics {
+                    previous_topics.entry(topic).or_insert(collector);
+                }
+            }
+            Err(error) => log::warn!("Could not load the previously published entities: {:#}", error),
+        }
+
+        let current_topics = self.discovery_topics();
+        let mut saved_topics = self.discovery_topic_owners();
+        let device_prefix = format!("/system-mqtt-{}/", self.device_id);
+        for (topic, collector) in previous_topics {
+            if current_topics.contains(&topic) {
+                continue;
+            }
+            let failed = collector.as_ref().is_some_and(|collector| failures.contains(collector));
+            if failed && topic.contains(&device_prefix) {
+                saved_topics.insert(topic, collector);
+            } else {
+                self.stale_topics.insert(topic);
+            }
+        }
+
+        self.retract_last_device_id().await;
+        self.stale_topics.retain(|topic| !saved_topics.contains_key(topic));
+        if let Err(error) = self.published_topics.save(&saved_topics).await {
+            log::warn!("Could not save the published entities: {:#}", error);
+        }
+
+        if self.connected {
+            self.retract_stale_topics().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Retire the device ID used by the last run if the ID changed, or remember the current one.
+    ///
+    /// The entities of a retired ID cannot be derived from the current ones, since entity IDs
+    /// were made differently back then. Instead, every retained discovery topic of the old ID is
+    /// retracted once connected, and the current ID is only remembered after that.
+    ///
+    /// Runs that did not record their device ID used the hostname, which was the default ID
+    /// before it was derived from the machine ID.
+    async fn retract_last_device_id(&mut self) {
+        let last_device_id = match self.last_device_id.load().await {
+            Ok(last_device_id) => last_device_id,
+            Err(error) => {
+                log::warn!("Could not load the last device ID: {:#}", error);
+                return;
+            }
+        };
+        if last_device_id.as_ref() == Some(&self.device_id) {
+            return;
+        }
+
+        let last_device_id = last_device_id.or_else(|| crate::device_info::hostname().ok());
+        if let Some(last_device_id) = last_device_id.filter(|id| *id != self.device_id) {
+            log::info!(
+                "The device ID changed from `{}` to `{}`, retracting the entities of the old ID.",
+                last_device_id, self.device_id
+            );
+            self.retired_device_id = Some(last_device_id);
+            return;
+        }
+
+        self.save_device_id().await;
+    }
+
+    async fn save_device_id(&self) {
+        if let Err(error) = self.last_device_id.save(&self.device_id).await {
+            log::warn!("Could not save the device ID: {:#}", error);
+        }
+    }
+
+    async fn retract_stale_topics(&mut self) -> Result<()> {
+        if self.retract_discovery(&self.stale_topics).await? {
+            self.stale_topics.clear();
+        }
+        Ok(())
+    }
+
+    /// Forget every registered entity and apply a new configuration.
+    ///
+    /// Returns the discovery topics that were registered before with their collectors, so that
+    /// entities which are not registered again can be retracted.
+    pub fn reset(&mut self, config: &Config) -> TopicOwners {
+        let previous_topics = self.discovery_topic_owners();
+
+        let heartbeat = config.publish.heartbeat.unwrap_or(Duration::from_secs(10 * 60));
+        self.device = crate::device_info::device(&self.device_id, config);
+        self.state_topic_mode = config.state_topic_mode;
+        self.publish_config = config.publish.clone();
+        self.entity_overrides = config.entities.clone();
+        self.deadband_filter = DeadbandFilter::new(heartbeat);
+        self.offline_queue = build_offline_queue(config);
+        self.published_topics = PublishedTopics::new(&config.state_dir());
+        self.last_device_id = LastDeviceId::new(&config.state_dir());
+        self.entity_ids = EntityIds::load(&config.state_dir());
+        self.status_topic = status_topic(config);
+        self.registered_topics.clear();
+        self.command_topics.clear();
+        self.discovery_info.clear();
+        self.discovery_collectors.clear();
+        self.state.clear();
+        self.registering_collector = None;
+        self.collector_availability.clear();
+        self.register_connectivity();
+
+        previous_topics
+    }
+
+    /// The discovery topics of all registered entities.
+    pub fn discovery_topics(&self) -> HashSet<String> {
+        self.discovery_info
+            .iter()
+            .map(|(topic, _)| topic.clone())
+            .collect()
+    }
+
+    /// The discovery topics of all registered entities, with the collectors that registered them.
+    fn discovery_topic_owners(&self) -> TopicOwners {
+        self.discovery_info
+            .iter()
+            .map(|(topic, _)| (topic.clone(), self.discovery_collectors.get(topic).cloned()))
+            .collect()
+    }
+
+    /// Remove entities from Home Assistant by clearing their retained discovery topics.
+    ///
+    /// Returns whether every entity was retracted before the connection was lost.
+    pub async fn retract_discovery(&self, topics: impl IntoIterator<Item = &String>) -> Result<bool> {
+        for topic in topics {
+            log::info!("Retracting entity at `{}`.", topic);
+            let sent = self
+                .request(self.client.publish(topic.clone(), QoS::AtLeastOnce, true, ""))
+                .await
+                .context("Failed to retract topic from MQTT server.")?;
+            if !sent {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
     pub async fn publish_discovery(&self) -> Result<()> {
         for (topic, payload) in &self.discovery_info {
             let message = serde_json::ser::to_string(payload)
                 .context("Failed to serialize topic information.")?;
-            self.client
-                .publish(topic.clone(), QoS::AtLeastOnce, true, message)
+            let sent = self
+                .request(self.client.publish(topic.clone(), QoS::AtLeastOnce, true, message))
                 .await
                 .context("Failed to publish topic to MQTT server.")?;
+            if !sent {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Merge freshly collected values into the device state and publish them.
+    ///
+    /// Values held back by their deadband are not published. In combined mode the complete
+    /// state is published as one JSON object whenever a value with a deadband moves past it, a
+    /// value without one changes, a new entity gets its first value, or the heartbeat elapses,
+    /// so every entity always has a value. In per-entity mode only the due values are published, each as a plain value on the
+    /// entity's own topic.
+    pub async fn update_state(&mut self, mut stats: HashMap<String, Value>) -> Result<()> {
+        let now = Instant::now();
+        stats.retain(|entity_id, _| !self.is_disabled(entity_id));
+
+        match self.state_topic_mode {
+            StateTopicMode::Combined => {
+                // Entities that never had a value are published right away.
+                let due = stats.keys().any(|entity_id| !self.state.contains_key(entity_id))
+                    || self.deadband_filter.is_batch_due(&stats, now);
+                self.state.extend(stats);
+
+                if due {
+                    self.publish_combined_state(now).await?;
+                }
+            }
+            StateTopicMode::PerEntity => {
+                for (entity_id, value) in stats {
+                    if self.deadband_filter.is_due(&entity_id, &value, now) {
+                        self.publish(&entity_id, plain_payload(&value)).await;
+                        self.deadband_filter.mark_published(&entity_id, &value, now);
+                    }
+                    self.state.insert(entity_id, value);
+                }
+            }
         }
 
         Ok(())
     }
 
+    /// Whether an entity, or the entity whose attributes are published under `entity_id`,
+    /// is disabled.
+    fn is_disabled(&self, entity_id: &str) -> bool {
+        let entity_id = entity_id.strip_suffix("_attributes").unwrap_or(entity_id);
+        self.entity_overrides
+            .get(entity_id)
+            .is_some_and(|overrides| overrides.disabled)
+    }
+
+    /// Publish the complete latest state, regardless of deadbands.
+    async fn republish_state(&mut self) -> Result<()> {
+        if self.state.is_empty() {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+
+        match self.state_topic_mode {
+            StateTopicMode::Combined => {
+                self.publish_combined_state(now).await?;
+            }
+            StateTopicMode::PerEntity => {
+                for (entity_id, value) in &self.state {
+                    self.publish(entity_id, plain_payload(value)).await;
+                    self.deadband_filter.mark_published(entity_id, value, now);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn publish_combined_state(&mut self, now: Instant) -> Result<()> {
+        let json_message = serde_json::to_string(&self.state)
+            .context("Failed to serialize stats to JSON.")?;
+        self.publish("state", json_message).await;
+        self.deadband_filter.mark_batch_published(&self.state, now);
+
+        Ok(())
+    }
+
     pub async fn publish(&self, topic_name: &str, value: String) {
         log::debug!("PUBLISH `{}` TO `{}`", value, topic_name);
 
         let topic = format!("system-mqtt/{}/{}", self.device_id, topic_name);
         if self.registered_topics.contains(&topic) {
-            if let Err(error) = self.client.publish(topic, QoS::AtLeastOnce, false, value).await {
-                log::error!("Failed to publish topic `{}`: {:#}", topic_name, error);
+            // Messages are only queued when they could not be sent because the connection is
+            // down, so that the queue never holds messages older than the ones already sent.
+            if self.connected {
+                match self.request(self.client.publish(&topic, QoS::AtLeastOnce, false, value.as_str())).await {
+                    Ok(true) => return,
+                    Ok(false) => {}
+                    Err(error) => {
+                        log::error!("Failed to publish topic `{}`: {:#}", topic_name, error);
+                        return;
+                    }
+                }
+            }
+
+            if let Some(offline_queue) = &self.offline_queue {
+                if let Err(error) = offline_queue.push(&topic, &value).await {
+                    log::error!("Failed to queue topic `{}`: {:#}", topic_name, error);
+                }
             }
         } else {
             log::error!(
@@ -207,8 +954,65 @@ impl HomeAssistant {
         }
     }
 
+    /// Publish the outcome of running the action behind a button.
+    pub async fn publish_action_result(&self, result: &ActionResult) -> Result<()> {
+        let payload = serde_json::to_string(result).context("Failed to serialize action result.")?;
+        self.publish(&format!("{}/result", result.entity_id), payload).await;
+        Ok(())
+    }
+
     pub async fn disconnect(&self) -> Result<()> {
-        self.set_available(false).await?;
+        self.request(self.client.disconnect())
+            .await
+            .context("Failed to disconnect from MQTT server.")?;
+
         Ok(())
     }
+
+    /// Hand a request to the MQTT event loop, giving up once the connection is lost.
+    ///
+    /// The event loop stops taking requests while it waits to reconnect, which can take
+    /// minutes, so waiting for room in its queue regardless would hold up the whole daemon.
+    /// Everything is published again once the connection is back. Returns whether the request
+    /// was handed over.
+    async fn request(&self, request: impl Future<Output = Result<(), ClientError>>) -> Result<bool> {
+        let mut connection = self.connection.clone();
+        tokio::select! {
+            result = request => {
+                result?;
+                Ok(true)
+            }
+            _ = connection.wait_for(|connected| !connected) => {
+                log::debug!("Connection to the MQTT broker lost, dropping request.");
+                Ok(false)
+            }
+        }
+    }
+}
+
+/// The key the JSON attributes of an entity are published under.
+pub fn attributes_id(entity_id: &str) -> String {
+    format!("{}_attributes", entity_id)
+}
+
+/// Format a value as the plain payload of a per-entity state topic.
+fn plain_payload(value: &Value) -> String {
+    match value {
+        Value::String(string) => string.clone(),
+        value => value.to_string(),
+    }
+}
+
+fn build_offline_queue(config: &Config) -> Option<OfflineQueue> {
+    config
+        .offline_queue
+        .as_ref()
+        .map(|queue_config| OfflineQueue::new(queue_config, &config.state_dir()))
+}
+
+fn status_topic(config: &Config) -> String {
+    config
+        .homeassistant_status_topic
+        .clone()
+        .unwrap_or_else(|| "homeassistant/status".to_string())
 }
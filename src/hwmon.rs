@@ -0,0 +1,306 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use anyhow::{bail, Context, Result};
+use futures_util::future::{BoxFuture, LocalBoxFuture};
+use serde_json::Value;
+use crate::collector::{read_blocking, Collector};
+use crate::config::LmSensorsConfig;
+use crate::home_assistant::{attributes_id, EntityRegistrationBuilder, HomeAssistant};
+use crate::sensor_filter::SensorFilter;
+
+/// A backend reading the hardware monitoring sensors of the machine, such as libsensors or
+/// sysfs.
+///
+/// Reading is synchronous, so it is run on the blocking thread pool.
+pub trait SensorSource: Send + 'static {
+    /// Read every sensor together with its current readings.
+    fn read(&mut self) -> Result<Vec<Sensor>>;
+}
+
+/// A sensor of a hardware monitoring chip, such as a temperature or a fan speed.
+pub struct Sensor {
+    /// The name of the chip, such as `coretemp-isa-0000`.
+    pub chip: String,
+    /// Identifies the sensor in a way that survives reboots, made of the device of the chip
+    /// and the name of the feature, such as `temp1`.
+    pub identity: String,
+    /// The label of the feature, such as `Package id 0`.
+    pub label: String,
+    pub kind: SensorKind,
+    /// The readings of the sensor, in the units libsensors reports them in.
+    pub readings: Vec<Reading>,
+}
+
+/// The kinds of sensors that are reported.
+#[derive(Clone, Copy)]
+pub enum SensorKind {
+    Voltage,
+    Fan,
+    Temperature,
+    Power,
+    Energy,
+    Current,
+    Humidity,
+}
+
+/// What a sub-feature of a sensor reports.
+pub enum Reading {
+    /// The current value of the sensor.
+    Input(f64),
+    /// The average value of the sensor, used for sensors that have no current value, such as
+    /// the power draw of many CPUs and GPUs.
+    Average(f64),
+    /// A limit of the sensor, published as a JSON attribute with the given name.
+    Threshold(&'static str, f64),
+    /// Whether an alarm of the sensor is raised, published as a binary sensor whose entity ID
+    /// is the one of the sensor followed by the given suffix.
+    Alarm(&'static str, bool),
+}
+
+/// How Home Assistant should present the sensors of a kind.
+struct KindMetadata {
+    device_class: Option<&'static str>,
+    state_class: &'static str,
+    unit: &'static str,
+    icon: &'static str,
+    /// The factor converting values from the unit libsensors reports them in to `unit`.
+    scale: f64,
+}
+
+impl SensorKind {
+    /// How Home Assistant should present sensors of this kind.
+    ///
+    /// libsensors reports voltages in V, fan speeds in RPM, temperatures in °C, power in W,
+    /// energy in J, currents in A and humidity in %RH. Energy is an ever-growing counter, which
+    /// is converted to kWh for the Home Assistant energy dashboard.
+    fn metadata(self) -> KindMetadata {
+        match self {
+            SensorKind::Voltage => KindMetadata {
+                device_class: Some("voltage"),
+                state_class: "measurement",
+                unit: "V",
+                icon: "mdi:flash",
+                scale: 1.0,
+            },
+            // Home Assistant has no device class for rotational speed.
+            SensorKind::Fan => KindMetadata {
+                device_class: None,
+                state_class: "measurement",
+                unit: "RPM",
+                icon: "mdi:fan",
+                scale: 1.0,
+            },
+            SensorKind::Temperature => KindMetadata {
+                device_class: Some("temperature"),
+                state_class: "measurement",
+                unit: "°C",
+                icon: "mdi:thermometer",
+                scale: 1.0,
+            },
+            SensorKind::Power => KindMetadata {
+                device_class: Some("power"),
+                state_class: "measurement",
+                unit: "W",
+                icon: "mdi:flash",
+                scale: 1.0,
+            },
+            SensorKind::Energy => KindMetadata {
+                device_class: Some("energy"),
+                state_class: "total_increasing",
+                unit: "kWh",
+                icon: "mdi:lightning-bolt",
+                scale: 1.0 / 3_600_000.0,
+            },
+            SensorKind::Current => KindMetadata {
+                device_class: Some("current"),
+                state_class: "measurement",
+                unit: "A",
+                icon: "mdi:current-dc",
+                scale: 1.0,
+            },
+            SensorKind::Humidity => KindMetadata {
+                device_class: Some("humidity"),
+                state_class: "measurement",
+                unit: "%",
+                icon: "mdi:water-percent",
+                scale: 1.0,
+            },
+        }
+    }
+
+    /// The name of the kind, as matched by the `kind` patterns of sensor filters.
+    pub fn name(self) -> &'static str {
+        match self {
+            SensorKind::Voltage => "voltage",
+            SensorKind::Fan => "fan",
+            SensorKind::Temperature => "temperature",
+            SensorKind::Power => "power",
+            SensorKind::Energy => "energy",
+            SensorKind::Current => "current",
+            SensorKind::Humidity => "humidity",
+        }
+    }
+}
+
+/// Identify the device an hwmon directory such as `/sys/class/hwmon/hwmon3` belongs to by its
+/// path below `/sys/devices`, which unlike the hwmon number does not change across reboots.
+///
+/// Virtual devices have no such path, so `None` is returned for them.
+pub fn device_identity(hwmon: &Path, sysfs_root: &Path) -> Option<String> {
+    let root = sysfs_root.canonicalize().ok()?;
+    let mut device = hwmon.canonicalize().ok()?;
+    if device.file_name().is_some_and(|name| name.to_string_lossy().starts_with("hwmon")) {
+        device.pop();
+    }
+    if device.file_name().is_some_and(|name| name == "hwmon") {
+        device.pop();
+    }
+
+    let relative = device.strip_prefix(&root).ok()?;
+    if relative.starts_with("devices/virtual") {
+        return None;
+    }
+
+    Some(Path::new("/sys").join(relative).display().to_string())
+}
+
+/// Collects the hardware monitoring sensors read by a [`SensorSource`].
+///
+/// Whichever backend is used, a sensor gets the same entity ID, unit and device class.
+pub struct HwmonCollector<S> {
+    source: Arc<Mutex<S>>,
+    filter: SensorFilter,
+    /// The names of the chips with registered sensors, each of which must still provide
+    /// values when collecting.
+    registered_chips: HashSet<String>,
+    /// The entity IDs of the registered sensors, by identity.
+    sensor_ids: HashMap<String, String>,
+}
+
+impl<S: SensorSource> HwmonCollector<S> {
+    pub fn new(source: S, config: &LmSensorsConfig) -> Result<Self> {
+        Ok(Self {
+            source: Arc::new(Mutex::new(source)),
+            filter: SensorFilter::new(config)?,
+            registered_chips: HashSet::new(),
+            sensor_ids: HashMap::new(),
+        })
+    }
+
+    pub async fn collect_values(&mut self, stats: &mut HashMap<String, Value>) -> Result<()> {
+        // The chips that still provide at least one value.
+        let mut chips = HashSet::new();
+        for sensor in read_blocking(&self.source, |source| source.read()).await? {
+            let Some(sensor_id) = self.sensor_ids.get(&sensor.identity) else {
+                continue;
+            };
+            let metadata = sensor.kind.metadata();
+            let collected = stats.len();
+
+            let mut input = None;
+            let mut average = None;
+            let mut attributes = serde_json::Map::new();
+            for reading in sensor.readings {
+                match reading {
+                    Reading::Input(v) => input = Some(v),
+                    Reading::Average(v) => average = Some(v),
+                    Reading::Threshold(name, v) => {
+                        attributes.insert(name.to_string(), Value::from(v * metadata.scale));
+                    }
+                    Reading::Alarm(suffix, raised) => {
+                        let state = if raised { "ON" } else { "OFF" };
+                        stats.insert(format!("{}_{}", sensor_id, suffix), Value::from(state));
+                    }
+                }
+            }
+
+            if let Some(v) = input.or(average) {
+                stats.insert(sensor_id.clone(), Value::from(v * metadata.scale));
+            }
+            if !attributes.is_empty() {
+                stats.insert(attributes_id(sensor_id), Value::Object(attributes));
+            }
+
+            if stats.len() > collected {
+                chips.insert(sensor.chip);
+            }
+        }
+
+        // A chip that disappeared takes its sensors with it.
+        let mut missing: Vec<_> = self.registered_chips.difference(&chips).collect();
+        if !missing.is_empty() {
+            missing.sort();
+            bail!("Chips {:?} no longer provide any values.", missing);
+        }
+
+        Ok(())
+    }
+
+    pub async fn register_sensors(&mut self, home_assistant: &mut HomeAssistant) -> Result<()> {
+        self.registered_chips.clear();
+        self.sensor_ids.clear();
+
+        for sensor in read_blocking(&self.source, |source| source.read()).await? {
+            if !self.filter.matches(&sensor.chip, &sensor.label, sensor.kind.name()) {
+                log::debug!("Skipping filtered sensor `{}` of chip `{}`.", sensor.label, sensor.chip);
+                continue;
+            }
+
+            let metadata = sensor.kind.metadata();
+            let identity = format!("lm_sensors:{}", sensor.identity);
+            let sensor_id = home_assistant.entity_id(&identity, &format!("{}_{}", sensor.chip, sensor.label));
+
+            let has_thresholds = sensor
+                .readings
+                .iter()
+                .any(|reading| matches!(reading, Reading::Threshold(..)));
+            let mut builder = EntityRegistrationBuilder::new("sensor", &sensor_id)
+                .state_class(metadata.state_class)
+                .unit_of_measurement(metadata.unit)
+                .icon(metadata.icon);
+            if let Some(device_class) = metadata.device_class {
+                builder = builder.device_class(device_class);
+            }
+            if has_thresholds {
+                builder = builder.json_attributes();
+            }
+            home_assistant
+                .register_entity_with_builder(builder)
+                .await
+                .with_context(|| format!("Failed to register {} sensor topic.", sensor.kind.name()))?;
+
+            for reading in &sensor.readings {
+                let Reading::Alarm(suffix, _) = reading else {
+                    continue;
+                };
+                home_assistant
+                    .register_entity_with_builder(
+                        EntityRegistrationBuilder::new("binary_sensor", &format!("{}_{}", sensor_id, suffix))
+                            .device_class("problem")
+                            .icon("mdi:alert")
+                    )
+                    .await
+                    .context("Failed to register alarm topic.")?;
+            }
+
+            self.sensor_ids.insert(sensor.identity, sensor_id);
+            self.registered_chips.insert(sensor.chip);
+        }
+        Ok(())
+    }
+}
+
+impl<S: SensorSource> Collector for HwmonCollector<S> {
+    fn name(&self) -> &str {
+        "lm_sensors"
+    }
+
+    fn register<'a>(&'a mut self, home_assistant: &'a mut HomeAssistant) -> LocalBoxFuture<'a, Result<()>> {
+        Box::pin(self.register_sensors(home_assistant))
+    }
+
+    fn collect<'a>(&'a mut self, stats: &'a mut HashMap<String, Value>) -> BoxFuture<'a, Result<()>> {
+        Box::pin(self.collect_values(stats))
+    }
+}
@@ -1,84 +1,21 @@
-use std::collections::HashMap;
+use std::path::Path;
 use anyhow::Context;
 use lm_sensors::feature::Kind;
-use lm_sensors::{LMSensors, Value};
-use crate::home_assistant::{HomeAssistant, EntityRegistrationBuilder};
-use crate::utils::sanitize_sensor_name;
+use lm_sensors::{ChipRef, FeatureRef, LMSensors, Value};
+use crate::hwmon::{device_identity, Reading, Sensor, SensorKind, SensorSource};
 
+/// Reads the hardware monitoring sensors through libsensors.
 pub struct SensorsImpl {
     pub sensors: LMSensors,
 }
 
 impl SensorsImpl {
     pub fn new() -> anyhow::Result<Self> {
-        let sensors = lm_sensors::Initializer::default().initialize()?;
+        let sensors = lm_sensors::Initializer::default()
+            .initialize()
+            .context("Failed to initialize libsensors.")?;
 
-        Ok(Self {
-            sensors,
-        })
-    }
-
-    pub async fn collect_values(&mut self, stats: &mut HashMap<String, serde_json::Value>) -> anyhow::Result<()> {
-        for chip in self.sensors.chip_iter(None) {
-            for feature in chip.feature_iter() {
-                let Some(feature_kind) = feature.kind() else {
-                    log::warn!("Failed to get feature from chip: {:?}", chip.name());
-                    continue;
-                };
-
-                let sensor_name = sanitize_sensor_name(format!(
-                    "{}_{}",
-                    chip.name()?,
-                    feature.label().unwrap_or("unknown".to_string())
-                ));
-
-                for sub_feature in feature.sub_feature_iter() {
-                    let val = sub_feature.value();
-
-                    match feature_kind {
-                        Kind::Voltage => {
-                            if let Ok(Value::VoltageInput(v)) = val {
-                                stats.insert(sensor_name.clone(), serde_json::Value::from(v));
-                            }
-                        }
-                        Kind::Temperature => {
-                            if let Ok(Value::TemperatureInput(v)) = val {
-                                stats.insert(sensor_name.clone(), serde_json::Value::from(v));
-                            }
-                        }
-                        Kind::Fan => {
-                            if let Ok(Value::FanInput(v)) = val {
-                                stats.insert(sensor_name.clone(), serde_json::Value::from(v));
-                            }
-                        }
-                        Kind::Power => {
-                            if let Ok(Value::PowerInput(v)) = val {
-                                stats.insert(sensor_name.clone(), serde_json::Value::from(v));
-                            }
-                        }
-                        Kind::Energy => {
-                            if let Ok(Value::EnergyInput(v)) = val {
-                                stats.insert(sensor_name.clone(), serde_json::Value::from(v));
-                            }
-                        }
-                        Kind::Current => {
-                            if let Ok(Value::CurrentInput(v)) = val {
-                                stats.insert(sensor_name.clone(), serde_json::Value::from(v));
-                            }
-                        }
-                        Kind::Humidity => {
-                            if let Ok(Value::HumidityInput(v)) = val {
-                                stats.insert(sensor_name.clone(), serde_json::Value::from(v));
-                            }
-                        }
-                        _ => {
-                            log::warn!("Unknown feature kind: {:?}", feature_kind);
-                        }
-                    }
-                }
-            }
-        }
-        Ok(())
+        Ok(Self { sensors })
     }
 
     // pub async fn print_sensors(&mut self) -> anyhow::Result<()> {
@@ -95,118 +32,119 @@ impl SensorsImpl {
     //     }
     //     Ok(())
     // }
+}
 
-    pub async fn register_sensors(&mut self, home_assistant: &mut HomeAssistant) -> anyhow::Result<()>{
+// SAFETY: libsensors keeps its state in globals rather than thread-locals, and only one
+// `LMSensors` can exist at a time. Collecting takes `&mut self`, so it is only ever used from one
+// thread at a time, which is all libsensors requires.
+unsafe impl Send for SensorsImpl {}
 
+impl SensorSource for SensorsImpl {
+    fn read(&mut self) -> anyhow::Result<Vec<Sensor>> {
+        let mut sensors = Vec::new();
         for chip in self.sensors.chip_iter(None) {
             for feature in chip.feature_iter() {
                 let Some(feature_kind) = feature.kind() else {
                     log::warn!("Failed to get feature from chip: {:?}", chip.name());
                     continue;
                 };
+                let Some(kind) = sensor_kind(feature_kind) else {
+                    continue;
+                };
 
-                let sensor_id = sanitize_sensor_name(format!(
-                    "{}_{}",
-                    chip.name()?,
-                    feature.label().unwrap_or("unknown".to_string())
-                ));
-
-                match feature_kind {
-                    Kind::Voltage => {
-                        home_assistant
-                            .register_entity_with_builder(
-                                EntityRegistrationBuilder::new("sensor", &sensor_id)
-                                    .device_class("voltage")
-                                    .state_class("measurement")
-                                    .unit_of_measurement("V")
-                                    .icon("mdi:flash")
-                            )
-                            .await
-                            .context("Failed to register voltage sensor topic.")?;
-                    }
-                    Kind::Fan => {
-                        home_assistant
-                            .register_entity_with_builder(
-                                EntityRegistrationBuilder::new("sensor", &sensor_id)
-                                    .device_class("fan")
-                                    .state_class("measurement")
-                                    .unit_of_measurement("RPM")
-                                    .icon("mdi:fan")
-                            )
-                            .await
-                            .context("Failed to register fan sensor topic.")?;
-                    }
-                    Kind::Temperature => {
-                        home_assistant
-                            .register_entity_with_builder(
-                                EntityRegistrationBuilder::new("sensor", &sensor_id)
-                                    .device_class("temperature")
-                                    .state_class("measurement")
-                                    .unit_of_measurement("°C")
-                                    .icon("mdi:thermometer")
-                            )
-                            .await
-                            .context("Failed to register temperature sensor topic.")?;
-                    }
-                    Kind::Power => {
-                        home_assistant
-                            .register_entity_with_builder(
-                                EntityRegistrationBuilder::new("sensor", &sensor_id)
-                                    .device_class("power")
-                                    .state_class("measurement")
-                                    .unit_of_measurement("W")
-                                    .icon("mdi:flash")
-                            )
-                            .await
-                            .context("Failed to register power sensor topic.")?;
-                    }
-                    Kind::Energy => {
-                        home_assistant
-                            .register_entity_with_builder(
-                                EntityRegistrationBuilder::new("sensor", &sensor_id)
-                                    .device_class("energy")
-                                    .state_class("measurement")
-                                    .unit_of_measurement("kWh")
-                                    .icon("mdi:flash")
-                            )
-                            .await
-                            .context("Failed to register energy sensor topic.")?;
-                    }
-                    Kind::Current => {
-                        home_assistant
-                            .register_entity_with_builder(
-                                EntityRegistrationBuilder::new("sensor", &sensor_id)
-                                    .device_class("current")
-                                    .state_class("measurement")
-                                    .unit_of_measurement("A")
-                                    .icon("mdi:flash")
-                            )
-                            .await
-                            .context("Failed to register current sensor topic.")?;
-                    }
-                    Kind::Humidity => {
-                        home_assistant
-                            .register_entity_with_builder(
-                                EntityRegistrationBuilder::new("sensor", &sensor_id)
-                                    .device_class("humidity")
-                                    .state_class("measurement")
-                                    .unit_of_measurement("%")
-                                    .icon("mdi:water-percent")
-                            )
-                            .await
-                            .context("Failed to register humidity sensor topic.")?;
-                    }
-                    Kind::VoltageID => {}
-                    Kind::Intrusion => {}
-                    Kind::BeepEnable => {}
-                    Kind::Unknown => {}
-                    _ => {
-                        log::warn!("Unknown feature kind: {:?}", feature_kind);
-                    }
-                }
+                sensors.push(Sensor {
+                    chip: chip.name()?,
+                    identity: format!("{}:{}", chip_identity(chip), feature_name(feature)),
+                    label: feature.label().unwrap_or("unknown".to_string()),
+                    kind,
+                    readings: feature
+                        .sub_feature_iter()
+                        .filter_map(|sub_feature| sub_feature.value().ok().and_then(reading))
+                        .collect(),
+                });
             }
         }
-        Ok(())
+
+        Ok(sensors)
     }
+}
+
+/// Identify a chip by the device it belongs to, which unlike the hwmon number and the order of
+/// the chips does not change across reboots.
+///
+/// Chips of virtual devices have no such path, and are identified by their name instead.
+fn chip_identity(chip: ChipRef) -> String {
+    let device = chip
+        .path()
+        .and_then(|path| device_identity(path, Path::new("/sys")));
 
-}
\ No newline at end of file
+    match (device, chip.name()) {
+        (Some(device), _) => device,
+        (None, Ok(name)) => name,
+        (None, Err(_)) => "unknown".to_string(),
+    }
+}
+
+/// The name of a feature within its chip, such as `temp1`.
+fn feature_name(feature: FeatureRef) -> String {
+    match feature.name() {
+        Some(Ok(name)) => name.to_string(),
+        _ => feature.number().to_string(),
+    }
+}
+
+/// The kind of sensor a feature is, or `None` if features of its kind are not reported.
+fn sensor_kind(kind: Kind) -> Option<SensorKind> {
+    let kind = match kind {
+        Kind::Voltage => SensorKind::Voltage,
+        Kind::Fan => SensorKind::Fan,
+        Kind::Temperature => SensorKind::Temperature,
+        Kind::Power => SensorKind::Power,
+        Kind::Energy => SensorKind::Energy,
+        Kind::Current => SensorKind::Current,
+        Kind::Humidity => SensorKind::Humidity,
+        Kind::VoltageID | Kind::Intrusion | Kind::BeepEnable | Kind::Unknown => return None,
+        _ => {
+            log::warn!("Unknown feature kind: {:?}", kind);
+            return None;
+        }
+    };
+
+    Some(kind)
+}
+
+/// Classify the value of a sub-feature, or `None` if it is not reported.
+fn reading(value: Value) -> Option<Reading> {
+    use Value::*;
+
+    let reading = match value {
+        VoltageInput(v) | FanInput(v) | TemperatureInput(v) | PowerInput(v) | EnergyInput(v)
+        | CurrentInput(v) | HumidityInput(v) => Reading::Input(v),
+        PowerAverage(v) | CurrentAverage(v) | VoltageAverage(v) => Reading::Average(v),
+
+        VoltageMinimum(v) | FanMinimum(v) | TemperatureMinimum(v) | PowerMinimum(v)
+        | CurrentMinimum(v) => Reading::Threshold("min", v),
+        VoltageMaximum(v) | FanMaximum(v) | TemperatureMaximum(v) | PowerMaximum(v)
+        | CurrentMaximum(v) => Reading::Threshold("max", v),
+        VoltageCritical(v) | TemperatureCritical(v) | PowerCritical(v)
+        | CurrentCritical(v) => Reading::Threshold("crit", v),
+
+        VoltageAlarm(a) | FanAlarm(a) | TemperatureAlarm(a) | PowerAlarm(a)
+        | CurrentAlarm(a) => Reading::Alarm("alarm", a),
+        VoltageMinimumAlarm(a) | FanMinimumAlarm(a) | TemperatureMinimumAlarm(a)
+        | PowerMinimumAlarm(a) | CurrentMinimumAlarm(a) => Reading::Alarm("min_alarm", a),
+        VoltageMaximumAlarm(a) | FanMaximumAlarm(a) | TemperatureMaximumAlarm(a)
+        | PowerMaximumAlarm(a) | CurrentMaximumAlarm(a) => Reading::Alarm("max_alarm", a),
+        VoltageCriticalAlarm(a) | TemperatureCriticalAlarm(a) | PowerCriticalAlarm(a)
+        | CurrentCriticalAlarm(a) => Reading::Alarm("crit_alarm", a),
+        VoltageLCriticalAlarm(a) | TemperatureLCriticalAlarm(a) | PowerLCriticalAlarm(a)
+        | CurrentLCriticalAlarm(a) => Reading::Alarm("lcrit_alarm", a),
+        TemperatureEmergencyAlarm(a) => Reading::Alarm("emergency_alarm", a),
+        PowerCapAlarm(a) => Reading::Alarm("cap_alarm", a),
+        FanFault(a) | TemperatureFault(a) => Reading::Alarm("fault", a),
+
+        _ => return None,
+    };
+
+    Some(reading)
+}
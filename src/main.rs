@@ -1,12 +1,24 @@
+mod actions;
 mod app;
 mod cli;
+mod collector;
 mod config;
+mod deadband;
+mod device_info;
 mod discovery;
+mod discovery_state;
+mod entity_ids;
 mod home_assistant;
+mod hwmon;
+#[cfg(feature = "lm-sensors")]
 mod lm_sensors_impl;
 mod mqtt;
+mod offline_queue;
 mod password;
+mod sensor_filter;
+mod sysfs_sensors;
 mod system_sensors;
+#[cfg(feature = "nvidia")]
 mod nvidia_gpu;
 mod utils;
 
@@ -16,8 +28,9 @@ use anyhow::Result;
 use log::Level;
 use systemd_journal_logger::JournalLog;
 use std::time::Duration;
+use tokio::sync::watch;
 use tokio::time;
-use tokio::signal;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio_util::sync::CancellationToken;
 
 #[tokio::main]
@@ -34,14 +47,36 @@ async fn main() -> Result<()> {
             }
 
             let config = load_config(&args.config_file).await?;
+            let (config_tx, config_rx) = watch::channel(config);
             let cancel_token = CancellationToken::new();
             let cancel_token_clone = cancel_token.clone();
             
-            // Spawn a task to handle Ctrl+C
+            // Spawn a task to handle SIGTERM (sent by systemd) and SIGINT (Ctrl+C)
+            let mut terminate = signal(SignalKind::terminate())?;
+            let mut interrupt = signal(SignalKind::interrupt())?;
             tokio::spawn(async move {
-                if let Ok(()) = signal::ctrl_c().await {
-                    log::info!("Terminate signal received. Initiating graceful shutdown...");
-                    cancel_token_clone.cancel();
+                tokio::select! {
+                    _ = terminate.recv() => {}
+                    _ = interrupt.recv() => {}
+                }
+                log::info!("Terminate signal received. Initiating graceful shutdown...");
+                cancel_token_clone.cancel();
+            });
+
+            // Spawn a task to reload the configuration on SIGHUP
+            let mut hangup = signal(SignalKind::hangup())?;
+            let config_file = args.config_file.clone();
+            tokio::spawn(async move {
+                while hangup.recv().await.is_some() {
+                    log::info!("Reload signal received. Reloading configuration...");
+                    match load_config(&config_file).await {
+                        Ok(config) => {
+                            config_tx.send_replace(config);
+                        }
+                        Err(error) => {
+                            log::error!("Failed to reload configuration, keeping the current one: {error:#}");
+                        }
+                    }
                 }
             });
             
@@ -53,9 +88,13 @@ async fn main() -> Result<()> {
                     return Ok(());
                 }
 
-                match app::App::new(config.clone(), cancel_token.clone()).await {
-                    Ok(mut app) => {
-                        if let Err(error) = app.run().await {
+                match app::App::new(config_rx.clone(), cancel_token.clone()).await {
+                    Ok(mut app) => match app.run().await {
+                        Ok(app::RunOutcome::Shutdown) => break,
+                        Ok(app::RunOutcome::Reconnect) => {
+                            log::info!("MQTT settings changed. Reconnecting...");
+                        }
+                        Err(error) => {
                             log::error!("Fatal error: {error:#}");
                             log::error!("Restarting in 60 seconds...");
                             
@@ -69,10 +108,8 @@ async fn main() -> Result<()> {
                                     return Ok(());
                                 }
                             }
-                        } else {
-                            break;
                         }
-                    }
+                    },
                     Err(error) => {
                         log::error!("Failed to initialize application: {error:#}");
                         log::error!("Restarting in 60 seconds...");
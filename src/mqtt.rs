@@ -1,10 +1,19 @@
 use anyhow::{Context, Result};
-use rumqttc::{MqttOptions, Transport, AsyncClient, ConnectionError, Event, Packet};
+use rumqttc::{MqttOptions, Transport, AsyncClient, Event, LastWill, Outgoing, Packet, Publish, QoS};
 use std::convert::TryFrom;
+use std::time::Duration;
 use tokio::fs;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::watch;
 use tokio::task::JoinHandle;
+use tokio::time;
 use crate::config::{Config, PasswordSource};
+#[cfg(not(feature = "keyring"))]
+use crate::config::missing_feature;
+use crate::home_assistant::availability_topic;
+#[cfg(feature = "keyring")]
 use crate::password::KEYRING_SERVICE_NAME;
+use crate::utils::with_jitter;
 
 /// Setup MQTT client with the given configuration.
 /// 
@@ -13,6 +22,7 @@ use crate::password::KEYRING_SERVICE_NAME;
 /// - Server connection details
 /// - TLS/SSL configuration
 /// - Authentication
+/// - The last will marking the device offline
 /// - Client ID generation
 /// 
 /// # Arguments
@@ -36,6 +46,13 @@ pub async fn setup_mqtt_client(config: &Config, device_id: &str) -> Result<(Asyn
     let mut mqtt_options = MqttOptions::try_from(url)
         .context("failed to create MQTT options")?;
 
+    // Have the broker mark the device offline if the connection is lost without a clean shutdown.
+    mqtt_options.set_last_will(LastWill::new(
+        availability_topic(device_id),
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
 
     if let Some(ca_cert) = &config.ca_cert {
         let ca_cert = fs::read(ca_cert)
@@ -61,6 +78,7 @@ pub async fn setup_mqtt_client(config: &Config, device_id: &str) -> Result<(Asyn
     // Set credentials if provided
     if let Some(username) = &config.username {
         let password = match &config.password_source {
+            #[cfg(feature = "keyring")]
             PasswordSource::Keyring => {
                 log::info!("Using system keyring for MQTT password source.");
                 let keyring = keyring::Entry::new(KEYRING_SERVICE_NAME, username)
@@ -69,6 +87,8 @@ pub async fn setup_mqtt_client(config: &Config, device_id: &str) -> Result<(Asyn
                     .get_password()
                     .context("Failed to get password from keyring. If you have not yet set the password, run `system-mqtt set-password`.")?
             }
+            #[cfg(not(feature = "keyring"))]
+            PasswordSource::Keyring => return Err(missing_feature("password_source: keyring", "keyring")),
             PasswordSource::SecretFile(file_path) => {
                 log::info!("Using hidden file for MQTT password source.");
                 let pass: String = fs::read_to_string(file_path)
@@ -89,36 +109,82 @@ pub async fn setup_mqtt_client(config: &Config, device_id: &str) -> Result<(Asyn
     Ok((client, eventloop))
 }
 
+/// The shortest delay before reconnecting to the MQTT broker.
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// The longest delay before reconnecting to the MQTT broker.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// Changes of the connection state reported by the MQTT event loop.
+pub enum MqttEvent {
+    /// The connection to the broker was (re-)established.
+    Connected,
+    /// The connection to the broker was lost.
+    Disconnected,
+    /// A message arrived on one of the subscribed topics.
+    Message(Publish),
+}
+
 /// Run the MQTT event loop in a separate task.
 /// 
 /// This function spawns a new task that handles MQTT connection events and maintains
 /// the connection to the MQTT broker. It monitors for:
 /// - Connection acknowledgments
-/// - Connection errors
+/// - Messages on subscribed topics
+/// - Disconnection requests
+/// - Connection errors, after which it reconnects with a capped exponential backoff
 /// - Other MQTT events
 /// 
 /// # Arguments
 /// 
 /// * `eventloop` - The MQTT event loop to run
+/// * `events` - Where changes of the connection state and incoming messages are reported
+/// * `connection` - Set to whether the event loop is connected, as soon as that changes
 /// 
 /// # Returns
 /// 
 /// A join handle that can be used to monitor the MQTT task's status
 pub async fn mqtt_loop(
-    mut eventloop: rumqttc::EventLoop
-) -> JoinHandle<std::result::Result<(), ConnectionError>> {
+    mut eventloop: rumqttc::EventLoop,
+    events: UnboundedSender<MqttEvent>,
+    connection: watch::Sender<bool>,
+) -> JoinHandle<()> {
     tokio::spawn(async move {
+        let mut reconnect_delay = MIN_RECONNECT_DELAY;
+        let mut connected = false;
+
         loop {
             match eventloop.poll().await {
                 Ok(Event::Incoming(Packet::ConnAck(_))) => {
                     log::info!("Connected to MQTT broker.");
+                    connected = true;
+                    connection.send_replace(true);
+                    reconnect_delay = MIN_RECONNECT_DELAY;
+                    let _ = events.send(MqttEvent::Connected);
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    let _ = events.send(MqttEvent::Message(publish));
+                }
+                Ok(Event::Outgoing(Outgoing::Disconnect)) => {
+                    log::info!("Disconnected from MQTT broker.");
+                    connection.send_replace(false);
+                    break;
                 }
                 Err(e) => {
-                    log::error!("Error in MQTT loop: {:#}", e);
-                    break Err(e);
+                    let delay = with_jitter(reconnect_delay, 0.2);
+                    log::error!("Error in MQTT loop: {:#}. Reconnecting in {:.1?}.", e, delay);
+
+                    if connected {
+                        connected = false;
+                        connection.send_replace(false);
+                        let _ = events.send(MqttEvent::Disconnected);
+                    }
+
+                    time::sleep(delay).await;
+                    reconnect_delay = (reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
                 }
                 _ => {}
             }
         }
     })
-}
\ No newline at end of file
+}
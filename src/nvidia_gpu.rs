@@ -1,14 +1,17 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use serde::Serialize;
 use std::process::Stdio;
 use tokio::{io::{AsyncBufReadExt, BufReader}, process::Command};
-use anyhow::{Result};
+use anyhow::{bail, Context, Result};
+use futures_util::future::{BoxFuture, LocalBoxFuture};
+use crate::collector::Collector;
 use crate::home_assistant::{EntityRegistrationBuilder, HomeAssistant};
-use crate::utils::sanitize_sensor_name;
+use crate::utils::slugify;
 
 #[derive(Debug, Serialize)]
 pub struct GpuInfo {
     pub index: u32,
+    pub uuid: String,
     pub name: String,
     pub temperature: u32,
     pub utilization: u32,
@@ -18,92 +21,179 @@ pub struct GpuInfo {
     pub power_draw: f64,
 }
 
+impl GpuInfo {
+    /// A stable identifier of the GPU, used in its entity IDs.
+    ///
+    /// This is the UUID of the GPU, so that identical cards do not collide and a card keeps its
+    /// entities when it moves to another slot. The index is used if there is no UUID.
+    pub fn id(&self) -> String {
+        let uuid = self.uuid.strip_prefix("GPU-").unwrap_or(&self.uuid);
+        if uuid.is_empty() {
+            self.index.to_string()
+        } else {
+            slugify(&uuid.to_lowercase())
+        }
+    }
+}
+
+/// A value reported for every GPU.
+///
+/// Both registration and collection go through this table, so they always report the same
+/// values.
+struct GpuMetric {
+    /// The suffix of the entity ID, after `gpu_<id>_`.
+    key: &'static str,
+    /// The suffix of the friendly name, after the model of the GPU.
+    name: &'static str,
+    device_class: Option<&'static str>,
+    unit: &'static str,
+    icon: &'static str,
+    value: fn(&GpuInfo) -> serde_json::Value,
+}
+
+const GPU_METRICS: &[GpuMetric] = &[
+    GpuMetric {
+        key: "temperature",
+        name: "temperature",
+        device_class: Some("temperature"),
+        unit: "°C",
+        icon: "mdi:thermometer",
+        value: |gpu| serde_json::Value::from(gpu.temperature),
+    },
+    GpuMetric {
+        key: "utilization",
+        name: "utilization",
+        device_class: None,
+        unit: "%",
+        icon: "mdi:percent",
+        value: |gpu| serde_json::Value::from(gpu.utilization),
+    },
+    GpuMetric {
+        key: "memory_used",
+        name: "memory used",
+        device_class: None,
+        unit: "MB",
+        icon: "mdi:memory",
+        value: |gpu| serde_json::Value::from(gpu.memory_used),
+    },
+    GpuMetric {
+        key: "power",
+        name: "power",
+        device_class: Some("power"),
+        unit: "W",
+        icon: "mdi:flash",
+        value: |gpu| serde_json::Value::from(gpu.power_draw),
+    },
+];
 
 pub struct NvidiaGpuSensors {
-    nvidia_smi_available: bool,
+    /// The IDs of the GPUs with registered sensors, each of which must still be reported
+    /// when collecting.
+    registered_gpus: HashSet<String>,
+    /// The entity IDs of the registered sensors, by GPU ID and metric key.
+    sensor_ids: HashMap<(String, &'static str), String>,
 }
 
 impl NvidiaGpuSensors {
     pub fn new() -> Self {
         Self {
-            nvidia_smi_available: false,
+            registered_gpus: HashSet::new(),
+            sensor_ids: HashMap::new(),
         }
     }
 
-    pub async fn init(&mut self) -> Result<()> {
-        let gpu_info = get_nvidia_gpu_info().await;
-        match gpu_info {
-            Ok(gpu_info) => {
-                self.nvidia_smi_available = true;
-                log::debug!("NVIDIA GPU info: {:?}", gpu_info);
-            }
-            Err(err) => {
-                log::debug!("Failed to get NVIDIA GPU info, nvidia sensors disabled: {err:#}");
-            }
-        }
-        Ok(())
+    /// Check that `nvidia-smi` can report the GPUs, returning whether it reports any.
+    ///
+    /// A machine without `nvidia-smi` has no GPUs to report. If it is installed but fails, for
+    /// example because the driver is not loaded yet, the collector is not started, so that the
+    /// entities of the GPUs are kept until it can.
+    pub async fn init(&mut self) -> Result<bool> {
+        let gpu_info = match get_nvidia_gpu_info().await {
+            Ok(gpu_info) => gpu_info,
+            Err(error) if is_not_found(&error) => return Ok(false),
+            Err(error) => return Err(error).context("Failed to get NVIDIA GPU info from nvidia-smi."),
+        };
+        log::debug!("NVIDIA GPU info: {:?}", gpu_info);
+        Ok(!gpu_info.is_empty())
     }
 
     pub async fn collect_values(&self, stats: &mut HashMap<String, serde_json::Value>) -> Result<()> {
-        if !self.nvidia_smi_available {
-            return Ok(());
-        }
         let gpu_info = get_nvidia_gpu_info().await?;
+        let mut gpus = HashSet::new();
         for gpu in gpu_info {
-            let gpu_name = sanitize_sensor_name(gpu.name);
-            stats.insert(format!("gpu_{}_temperature", gpu_name), serde_json::Value::from(gpu.temperature));
-            stats.insert(format!("gpu_{}_utilization", gpu_name), serde_json::Value::from(gpu.utilization));
-            stats.insert(format!("gpu_{}_memory_used", gpu_name), serde_json::Value::from(gpu.memory_used));
-            stats.insert(format!("gpu_{}_power_draw", gpu_name), serde_json::Value::from(gpu.power_draw));
+            let id = gpu.id();
+            for metric in GPU_METRICS {
+                if let Some(entity_id) = self.sensor_ids.get(&(id.clone(), metric.key)) {
+                    stats.insert(entity_id.clone(), (metric.value)(&gpu));
+                }
+            }
+            gpus.insert(id);
         }
+
+        let mut missing: Vec<_> = self.registered_gpus.difference(&gpus).collect();
+        if !missing.is_empty() {
+            missing.sort();
+            bail!("GPUs {:?} are no longer reported by nvidia-smi.", missing);
+        }
+
         Ok(())
     }
 
-    pub async fn register_sensors(&self, home_assistant: &mut HomeAssistant) -> Result<()> {
-        if !self.nvidia_smi_available {
-            return Ok(());
-        }
+    pub async fn register_sensors(&mut self, home_assistant: &mut HomeAssistant) -> Result<()> {
+        self.registered_gpus.clear();
+        self.sensor_ids.clear();
         let gpu_info = get_nvidia_gpu_info().await?;
         for gpu in gpu_info {
-            let gpu_name = sanitize_sensor_name(gpu.name);
-            home_assistant
-                .register_entity_with_builder(
-                    EntityRegistrationBuilder::new("sensor", &format!("gpu_{}_temperature", gpu_name))
-                        .unit_of_measurement("°C")
-                        .icon("mdi:thermometer")
-                )
-                .await?;
-            home_assistant
-                .register_entity_with_builder(
-                    EntityRegistrationBuilder::new("sensor", &format!("gpu_{}_utilization", gpu_name))
-                        .unit_of_measurement("%")
-                        .icon("mdi:percent")
-                )
-                .await?;
-            home_assistant
-                .register_entity_with_builder(
-                    EntityRegistrationBuilder::new("sensor", &format!("gpu_{}_memory_used", gpu_name))
-                        .unit_of_measurement("MB")
-                        .icon("mdi:memory")
-                )
-                .await?;
-
-            home_assistant
-                .register_entity_with_builder(
-                    EntityRegistrationBuilder::new("sensor", &format!("gpu_{}_power", gpu_name))
-                        .unit_of_measurement("W")
-                        .icon("mdi:flash")
-                )
-                .await?;
+            let id = gpu.id();
+            for metric in GPU_METRICS {
+                let identity = format!("nvidia_gpu:{}:{}", id, metric.key);
+                let entity_id = home_assistant.entity_id(&identity, &format!("gpu_{}_{}", id, metric.key));
+                let name = format!("{} (GPU {}) {}", gpu.name, gpu.index, metric.name);
+                let mut builder = EntityRegistrationBuilder::new("sensor", &entity_id)
+                    .name(&name)
+                    .state_class("measurement")
+                    .unit_of_measurement(metric.unit)
+                    .icon(metric.icon);
+                if let Some(device_class) = metric.device_class {
+                    builder = builder.device_class(device_class);
+                }
+
+                home_assistant
+                    .register_entity_with_builder(builder)
+                    .await?;
+                self.sensor_ids.insert((id.clone(), metric.key), entity_id);
+            }
+            self.registered_gpus.insert(id);
         }
         Ok(())
     }
 }
 
+impl Collector for NvidiaGpuSensors {
+    fn name(&self) -> &str {
+        "nvidia_gpu"
+    }
+
+    fn register<'a>(&'a mut self, home_assistant: &'a mut HomeAssistant) -> LocalBoxFuture<'a, Result<()>> {
+        Box::pin(self.register_sensors(home_assistant))
+    }
+
+    fn collect<'a>(&'a mut self, stats: &'a mut HashMap<String, serde_json::Value>) -> BoxFuture<'a, Result<()>> {
+        Box::pin(self.collect_values(stats))
+    }
+}
+
+/// Whether an error is `nvidia-smi` not being installed.
+fn is_not_found(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<std::io::Error>()
+        .is_some_and(|error| error.kind() == std::io::ErrorKind::NotFound)
+}
+
 pub async fn get_nvidia_gpu_info() -> Result<Vec<GpuInfo>> {
     let output = Command::new("nvidia-smi")
         .args([
-            "--query-gpu=index,name,temperature.gpu,utilization.gpu,memory.total,memory.used,memory.free,power.draw",
+            "--query-gpu=index,uuid,name,temperature.gpu,utilization.gpu,memory.total,memory.used,memory.free,power.draw",
             "--format=csv,noheader,nounits",
         ])
         .stdout(Stdio::piped())
@@ -118,19 +208,20 @@ pub async fn get_nvidia_gpu_info() -> Result<Vec<GpuInfo>> {
 
     while let Some(line) = reader.next_line().await? {
         let fields: Vec<&str> = line.trim().split(',').map(str::trim).collect();
-        if fields.len() != 8 {
+        if fields.len() != 9 {
             continue; // skip malformed lines
         }
 
         let info = GpuInfo {
             index: fields[0].parse()?,
-            name: fields[1].to_string(),
-            temperature: fields[2].parse()?,
-            utilization: fields[3].parse()?,
-            memory_total: fields[4].parse()?,
-            memory_used: fields[5].parse()?,
-            memory_free: fields[6].parse()?,
-            power_draw: fields[7].parse()?,
+            uuid: fields[1].to_string(),
+            name: fields[2].to_string(),
+            temperature: fields[3].parse()?,
+            utilization: fields[4].parse()?,
+            memory_total: fields[5].parse()?,
+            memory_used: fields[6].parse()?,
+            memory_free: fields[7].parse()?,
+            power_draw: fields[8].parse()?,
         };
 
         gpu_info_list.push(info);
@@ -0,0 +1,157 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use crate::config::OfflineQueueConfig;
+
+/// A state message that could not be published while the broker was unreachable.
+#[derive(Serialize, Deserialize)]
+pub struct QueuedMessage {
+    pub topic: String,
+    pub payload: String,
+    /// When the message was produced, in seconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+/// A bounded on-disk queue of state messages, kept while the broker is unreachable.
+///
+/// Messages are appended to a file with one JSON object per line. When the file grows past
+/// its size limit the oldest messages are dropped, and messages older than the age limit
+/// are dropped when the queue is drained.
+pub struct OfflineQueue {
+    path: PathBuf,
+    max_size: u64,
+    max_age: Duration,
+    include_timestamps: bool,
+}
+
+impl OfflineQueue {
+    pub fn new(config: &OfflineQueueConfig, state_dir: &Path) -> Self {
+        Self {
+            path: state_dir.join("offline-queue.jsonl"),
+            max_size: config.max_size.unwrap_or(1024 * 1024),
+            max_age: config.max_age.unwrap_or(Duration::from_secs(24 * 60 * 60)),
+            include_timestamps: config.include_timestamps,
+        }
+    }
+
+    /// Append a message to the end of the queue.
+    pub async fn push(&self, topic: &str, payload: &str) -> Result<()> {
+        let message = QueuedMessage {
+            topic: topic.to_string(),
+            payload: payload.to_string(),
+            timestamp: unix_timestamp(),
+        };
+        let mut line = serde_json::to_string(&message).context("Failed to serialize queued message.")?;
+        line.push('\n');
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)
+                .await
+                .context("Failed to create offline queue directory.")?;
+        }
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .context("Failed to open offline queue.")?;
+        file.write_all(line.as_bytes())
+            .await
+            .context("Failed to write to offline queue.")?;
+
+        if file.metadata().await?.len() > self.max_size {
+            self.trim().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove every message from the queue, returning the ones that have not expired in the
+    /// order they were queued.
+    ///
+    /// If timestamps are enabled, the time each message was produced is added to JSON object
+    /// payloads as a `timestamp` field.
+    pub async fn drain(&self) -> Result<Vec<QueuedMessage>> {
+        let mut messages = self.read().await?;
+        if messages.is_empty() {
+            return Ok(messages);
+        }
+
+        fs::remove_file(&self.path)
+            .await
+            .context("Failed to remove offline queue.")?;
+
+        let oldest = unix_timestamp().saturating_sub(self.max_age.as_secs());
+        messages.retain(|message| message.timestamp >= oldest);
+
+        if self.include_timestamps {
+            for message in &mut messages {
+                if let Ok(Value::Object(mut object)) = serde_json::from_str::<Value>(&message.payload) {
+                    object.insert("timestamp".to_string(), Value::from(message.timestamp));
+                    message.payload = Value::Object(object).to_string();
+                }
+            }
+        }
+
+        Ok(messages)
+    }
+
+    async fn read(&self) -> Result<Vec<QueuedMessage>> {
+        let contents = match fs::read_to_string(&self.path).await {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(error) => return Err(error).context("Failed to read offline queue."),
+        };
+
+        Ok(contents
+            .lines()
+            .filter_map(|line| match serde_json::from_str(line) {
+                Ok(message) => Some(message),
+                Err(error) => {
+                    log::warn!("Skipping corrupt offline queue entry: {:#}", error);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    /// Drop the oldest messages until the queue is back within three quarters of its size
+    /// limit, so it does not have to be rewritten on every following push.
+    async fn trim(&self) -> Result<()> {
+        let contents = fs::read_to_string(&self.path)
+            .await
+            .context("Failed to read offline queue.")?;
+
+        let target = self.max_size * 3 / 4;
+        let mut size = contents.len() as u64;
+        let mut lines = contents.lines();
+        while size > target {
+            match lines.next() {
+                Some(line) => size -= line.len() as u64 + 1,
+                None => break,
+            }
+        }
+
+        let mut remaining = lines.collect::<Vec<_>>().join("\n");
+        if !remaining.is_empty() {
+            remaining.push('\n');
+        }
+
+        log::warn!("Offline queue is full, dropping its oldest messages.");
+        fs::write(&self.path, remaining)
+            .await
+            .context("Failed to write offline queue.")
+    }
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
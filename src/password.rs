@@ -1,9 +1,15 @@
-use anyhow::{Context, Result, bail};
+use anyhow::Result;
+#[cfg(feature = "keyring")]
+use anyhow::{Context, bail};
 use crate::config::Config;
+#[cfg(not(feature = "keyring"))]
+use crate::config::missing_feature;
 
+#[cfg(feature = "keyring")]
 pub const KEYRING_SERVICE_NAME: &str = "system-mqtt";
 
 /// Set the password for the MQTT server in the system keyring
+#[cfg(feature = "keyring")]
 pub async fn set_password(config: Config) -> Result<()> {
     if let Some(username) = config.username {
         let password = rpassword::prompt_password("Password: ")
@@ -17,4 +23,10 @@ pub async fn set_password(config: Config) -> Result<()> {
     } else {
         bail!("You must set the username for login with the mqtt server before you can set the user's password")
     }
-}
\ No newline at end of file
+}
+
+/// Without keyring support, the password can only come from a file or the configuration.
+#[cfg(not(feature = "keyring"))]
+pub async fn set_password(_config: Config) -> Result<()> {
+    Err(missing_feature("set-password", "keyring"))
+}
@@ -0,0 +1,61 @@
+use anyhow::{Context, Result};
+use glob::Pattern;
+use crate::config::{LmSensorsConfig, SensorFilterRule};
+
+/// Decides which hardware sensors are reported, based on the include and exclude rules
+/// in the configuration.
+pub struct SensorFilter {
+    include: Vec<CompiledRule>,
+    exclude: Vec<CompiledRule>,
+}
+
+struct CompiledRule {
+    chip: Option<Pattern>,
+    feature: Option<Pattern>,
+    kind: Option<Pattern>,
+}
+
+impl SensorFilter {
+    pub fn new(config: &LmSensorsConfig) -> Result<Self> {
+        Ok(Self {
+            include: compile_rules(&config.include).context("Invalid lm-sensors include rule.")?,
+            exclude: compile_rules(&config.exclude).context("Invalid lm-sensors exclude rule.")?,
+        })
+    }
+
+    /// Whether the sensor of a chip with the given feature label and kind is reported.
+    pub fn matches(&self, chip: &str, feature: &str, kind: &str) -> bool {
+        let included = self.include.is_empty() || self.include.iter().any(|rule| rule.matches(chip, feature, kind));
+        included && !self.exclude.iter().any(|rule| rule.matches(chip, feature, kind))
+    }
+}
+
+impl CompiledRule {
+    fn matches(&self, chip: &str, feature: &str, kind: &str) -> bool {
+        let matches = |pattern: &Option<Pattern>, value: &str| {
+            pattern.as_ref().is_none_or(|pattern| pattern.matches(value))
+        };
+
+        matches(&self.chip, chip) && matches(&self.feature, feature) && matches(&self.kind, kind)
+    }
+}
+
+fn compile_rules(rules: &[SensorFilterRule]) -> Result<Vec<CompiledRule>> {
+    let compile = |pattern: &Option<String>| -> Result<Option<Pattern>> {
+        pattern
+            .as_deref()
+            .map(|pattern| Pattern::new(pattern).with_context(|| format!("Invalid pattern `{}`.", pattern)))
+            .transpose()
+    };
+
+    rules
+        .iter()
+        .map(|rule| {
+            Ok(CompiledRule {
+                chip: compile(&rule.chip)?,
+                feature: compile(&rule.feature)?,
+                kind: compile(&rule.kind)?,
+            })
+        })
+        .collect()
+}
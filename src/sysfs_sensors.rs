@@ -0,0 +1,477 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use crate::hwmon::{device_identity, Reading, Sensor, SensorKind, SensorSource};
+
+/// Reads the hardware monitoring sensors straight from sysfs, without libsensors.
+///
+/// Chips are read from `class/hwmon` and named the way libsensors names them, so both backends
+/// produce the same entity IDs. Thermal zones in `class/thermal` that have no hwmon chip of
+/// their own are reported as well.
+pub struct SysfsSensors {
+    root: PathBuf,
+}
+
+impl SysfsSensors {
+    /// Read the sysfs tree mounted at `root`, usually `/sys`.
+    pub fn new(root: &Path) -> Self {
+        Self {
+            root: root.to_path_buf(),
+        }
+    }
+
+    fn read_chip(&self, hwmon: &Path, sensors: &mut Vec<Sensor>) -> Option<String> {
+        let prefix = read_string(&hwmon.join("name"))?;
+        let chip = self.chip_name(&prefix, hwmon);
+        let device = device_identity(hwmon, &self.root).unwrap_or_else(|| chip.clone());
+
+        // The attributes of every feature, such as `input` for `temp1_input`, by feature name.
+        let mut features: BTreeMap<String, Vec<(String, PathBuf)>> = BTreeMap::new();
+        for entry in fs::read_dir(hwmon).ok()?.flatten() {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            let Some((feature, item)) = file_name.split_once('_') else {
+                continue;
+            };
+            features
+                .entry(feature.to_string())
+                .or_default()
+                .push((item.to_string(), entry.path()));
+        }
+
+        for (feature, items) in features {
+            let Some(kind) = feature_kind(&feature) else {
+                continue;
+            };
+            let label = items
+                .iter()
+                .find(|(item, _)| item == "label")
+                .and_then(|(_, path)| read_string(path))
+                .unwrap_or_else(|| feature.clone());
+            let readings = items
+                .iter()
+                .filter_map(|(item, path)| reading(kind, item, read_number(path)?))
+                .collect();
+
+            sensors.push(Sensor {
+                chip: chip.clone(),
+                identity: format!("{}:{}", device, feature),
+                label,
+                kind,
+                readings,
+            });
+        }
+
+        Some(prefix)
+    }
+
+    /// The name libsensors gives a chip, such as `coretemp-isa-0000`, made of the name of its
+    /// driver and the bus and address of its device.
+    ///
+    /// Devices that are not on a bus themselves, such as NVMe controllers, are named after
+    /// the closest parent that is.
+    fn chip_name(&self, prefix: &str, hwmon: &Path) -> String {
+        let devices = self.root.join("devices").canonicalize().unwrap_or_default();
+        let Ok(mut device) = hwmon.join("device").canonicalize() else {
+            return format!("{}-virtual-0", prefix);
+        };
+
+        while device.starts_with(&devices) && device != devices {
+            let subsystem = fs::canonicalize(device.join("subsystem"))
+                .ok()
+                .and_then(|path| Some(path.file_name()?.to_string_lossy().into_owned()));
+            let name = device.file_name().map(|name| name.to_string_lossy().into_owned());
+            if let (Some(subsystem), Some(name)) = (subsystem, name) {
+                if let Some(bus) = bus_address(&subsystem, &name) {
+                    return format!("{}-{}", prefix, bus);
+                }
+            }
+            if !device.pop() {
+                break;
+            }
+        }
+
+        format!("{}-virtual-0", prefix)
+    }
+
+    /// Read the thermal zones that are not already covered by an hwmon chip.
+    ///
+    /// The hwmon chips the kernel creates for thermal zones are named after the type of the
+    /// zone, with dashes replaced by underscores.
+    fn read_thermal_zones(&self, hwmon_names: &HashSet<String>, sensors: &mut Vec<Sensor>) {
+        let Ok(entries) = fs::read_dir(self.root.join("class/thermal")) else {
+            return;
+        };
+        let mut zones: Vec<_> = entries
+            .flatten()
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with("thermal_zone"))
+            .map(|entry| entry.path())
+            .collect();
+        zones.sort();
+
+        for zone in zones {
+            let Some(zone_type) = read_string(&zone.join("type")) else {
+                continue;
+            };
+            if hwmon_names.contains(&zone_type.replace('-', "_")) {
+                continue;
+            }
+            let Some(zone_name) = zone.file_name().map(|name| name.to_string_lossy().into_owned()) else {
+                continue;
+            };
+
+            let mut readings = Vec::new();
+            if let Some(temperature) = read_number(&zone.join("temp")) {
+                readings.push(Reading::Input(temperature / 1000.0));
+            }
+            for trip_point in 0.. {
+                let Some(trip_type) = read_string(&zone.join(format!("trip_point_{}_type", trip_point))) else {
+                    break;
+                };
+                let name = match trip_type.as_str() {
+                    "critical" => "crit",
+                    "hot" => "max",
+                    _ => continue,
+                };
+                if let Some(temperature) = read_number(&zone.join(format!("trip_point_{}_temp", trip_point))) {
+                    readings.push(Reading::Threshold(name, temperature / 1000.0));
+                }
+            }
+
+            sensors.push(Sensor {
+                identity: format!("{}:temp1", zone_name),
+                chip: zone_name,
+                label: zone_type,
+                kind: SensorKind::Temperature,
+                readings,
+            });
+        }
+    }
+}
+
+impl SensorSource for SysfsSensors {
+    fn read(&mut self) -> Result<Vec<Sensor>> {
+        let hwmon_dir = self.root.join("class/hwmon");
+        let mut chips: Vec<_> = fs::read_dir(&hwmon_dir)
+            .with_context(|| format!("Failed to read `{}`.", hwmon_dir.display()))?
+            .flatten()
+            .map(|entry| entry.path())
+            .collect();
+        chips.sort();
+
+        let mut sensors = Vec::new();
+        let mut hwmon_names = HashSet::new();
+        for chip in chips {
+            match self.read_chip(&chip, &mut sensors) {
+                Some(prefix) => {
+                    hwmon_names.insert(prefix);
+                }
+                None => log::debug!("Skipping unreadable hwmon chip `{}`.", chip.display()),
+            }
+        }
+        self.read_thermal_zones(&hwmon_names, &mut sensors);
+
+        Ok(sensors)
+    }
+}
+
+/// The bus and address part of a libsensors chip name, such as `pci-0100` for the PCI device
+/// `0000:01:00.0`, or `None` if libsensors does not know the bus.
+fn bus_address(subsystem: &str, device: &str) -> Option<String> {
+    let address = match subsystem {
+        "i2c" => {
+            let (bus, address) = device.split_once('-')?;
+            format!("i2c-{}-{:02x}", bus.parse::<u16>().ok()?, u32::from_str_radix(address, 16).ok()?)
+        }
+        "spi" => {
+            let (bus, chip_select) = device.strip_prefix("spi")?.split_once('.')?;
+            format!("spi-{}-{:x}", bus.parse::<u16>().ok()?, chip_select.parse::<u32>().ok()?)
+        }
+        "pci" => {
+            let (domain, rest) = device.split_once(':')?;
+            let (bus, rest) = rest.split_once(':')?;
+            let (slot, function) = rest.split_once('.')?;
+            let hex = |value: &str| u32::from_str_radix(value, 16).ok();
+            let address = (hex(domain)? << 16) + (hex(bus)? << 8) + (hex(slot)? << 3) + hex(function)?;
+            format!("pci-{:04x}", address)
+        }
+        // Platform devices are named after their driver and, for ISA chips, their I/O port.
+        "platform" | "of_platform" => {
+            let address = device
+                .rsplit_once('.')
+                .and_then(|(_, address)| address.parse::<u32>().ok())
+                .unwrap_or(0);
+            format!("isa-{:04x}", address)
+        }
+        "acpi" => "acpi-0".to_string(),
+        "hid" => {
+            let (bus, rest) = device.split_once(':')?;
+            let (_, id) = rest.rsplit_once('.')?;
+            format!("hid-{}-{:x}", u16::from_str_radix(bus, 16).ok()?, u32::from_str_radix(id, 16).ok()?)
+        }
+        "mdio_bus" => {
+            let (_, address) = device.rsplit_once(':')?;
+            format!("mdio-{:x}", u32::from_str_radix(address, 16).ok()?)
+        }
+        "scsi" => {
+            let fields: Vec<_> = device.split(':').collect();
+            let [host, _, id, _] = fields[..] else {
+                return None;
+            };
+            format!("scsi-{}-{:x}", host.parse::<u16>().ok()?, id.parse::<u32>().ok()?)
+        }
+        _ => return None,
+    };
+
+    Some(address)
+}
+
+/// The kind of sensor behind a feature name such as `temp1`, or `None` if it is not reported.
+fn feature_kind(feature: &str) -> Option<SensorKind> {
+    let prefix = feature.trim_end_matches(|c: char| c.is_ascii_digit());
+    if prefix.len() == feature.len() {
+        return None;
+    }
+
+    let kind = match prefix {
+        "in" => SensorKind::Voltage,
+        "fan" => SensorKind::Fan,
+        "temp" => SensorKind::Temperature,
+        "power" => SensorKind::Power,
+        "energy" => SensorKind::Energy,
+        "curr" => SensorKind::Current,
+        "humidity" => SensorKind::Humidity,
+        _ => return None,
+    };
+
+    Some(kind)
+}
+
+/// Classify an attribute of a feature, or `None` if it is not reported.
+///
+/// sysfs reports voltages, temperatures, currents and humidity in thousandths, and power and
+/// energy in millionths of the units libsensors reports them in.
+fn reading(kind: SensorKind, item: &str, value: f64) -> Option<Reading> {
+    use SensorKind::*;
+
+    let scaled = match kind {
+        Voltage | Temperature | Current | Humidity => value / 1000.0,
+        Power | Energy => value / 1_000_000.0,
+        Fan => value,
+    };
+    let raised = value != 0.0;
+
+    let reading = match (item, kind) {
+        ("input", _) => Reading::Input(scaled),
+        ("average", Power | Current | Voltage) => Reading::Average(scaled),
+
+        ("min", Voltage | Fan | Temperature | Power | Current) => Reading::Threshold("min", scaled),
+        ("max", Voltage | Fan | Temperature | Power | Current) => Reading::Threshold("max", scaled),
+        ("crit", Voltage | Temperature | Power | Current) => Reading::Threshold("crit", scaled),
+
+        ("alarm", Voltage | Fan | Temperature | Power | Current) => Reading::Alarm("alarm", raised),
+        ("min_alarm", Voltage | Fan | Temperature | Power | Current) => Reading::Alarm("min_alarm", raised),
+        ("max_alarm", Voltage | Fan | Temperature | Power | Current) => Reading::Alarm("max_alarm", raised),
+        ("crit_alarm", Voltage | Temperature | Power | Current) => Reading::Alarm("crit_alarm", raised),
+        ("lcrit_alarm", Voltage | Temperature | Power | Current) => Reading::Alarm("lcrit_alarm", raised),
+        ("emergency_alarm", Temperature) => Reading::Alarm("emergency_alarm", raised),
+        ("cap_alarm", Power) => Reading::Alarm("cap_alarm", raised),
+        ("fault", Fan | Temperature) => Reading::Alarm("fault", raised),
+
+        _ => return None,
+    };
+
+    Some(reading)
+}
+
+fn read_string(path: &Path) -> Option<String> {
+    let value = fs::read_to_string(path).ok()?;
+    Some(value.trim().to_string())
+}
+
+fn read_number(path: &Path) -> Option<f64> {
+    read_string(path)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    /// A fake sysfs tree in a temporary directory, removed when dropped.
+    struct FakeSysfs {
+        root: PathBuf,
+    }
+
+    impl FakeSysfs {
+        fn new(name: &str) -> Self {
+            let root = std::env::temp_dir().join(format!("system-mqtt-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(&root).unwrap();
+            Self { root }
+        }
+
+        fn file(&self, path: &str, contents: &str) {
+            let path = self.root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, format!("{}\n", contents)).unwrap();
+        }
+
+        fn dir(&self, path: &str) {
+            fs::create_dir_all(self.root.join(path)).unwrap();
+        }
+
+        fn link(&self, path: &str, target: &str) {
+            let path = self.root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            symlink(target, path).unwrap();
+        }
+
+        /// Add an hwmon chip at `device/hwmon/hwmonN`, or at `device/hwmonN` if `nested` is
+        /// false, with its attributes.
+        fn hwmon(&self, number: u32, device: &str, nested: bool, attributes: &[(&str, &str)]) {
+            let hwmon = if nested {
+                format!("{}/hwmon/hwmon{}", device, number)
+            } else {
+                format!("{}/hwmon{}", device, number)
+            };
+            for (name, value) in attributes {
+                self.file(&format!("{}/{}", hwmon, name), value);
+            }
+            let device_link = if nested { "../.." } else { ".." };
+            self.link(&format!("{}/device", hwmon), device_link);
+            self.link(&format!("class/hwmon/hwmon{}", number), &format!("../../{}", hwmon));
+        }
+    }
+
+    impl Drop for FakeSysfs {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    /// The readings of a sensor, such as `input=45`, sorted.
+    fn readings(sensor: &Sensor) -> Vec<String> {
+        let mut readings: Vec<_> = sensor
+            .readings
+            .iter()
+            .map(|reading| match reading {
+                Reading::Input(value) => format!("input={}", value),
+                Reading::Average(value) => format!("average={}", value),
+                Reading::Threshold(name, value) => format!("{}={}", name, value),
+                Reading::Alarm(name, raised) => format!("{}={}", name, raised),
+            })
+            .collect();
+        readings.sort();
+        readings
+    }
+
+    fn find<'a>(sensors: &'a [Sensor], identity: &str) -> &'a Sensor {
+        sensors
+            .iter()
+            .find(|sensor| sensor.identity == identity)
+            .unwrap_or_else(|| panic!("No sensor `{}`.", identity))
+    }
+
+    #[test]
+    fn reads_hwmon_chips_and_thermal_zones() {
+        let sysfs = FakeSysfs::new("sysfs-sensors");
+        sysfs.dir("bus/platform");
+        sysfs.dir("bus/pci");
+        sysfs.dir("class/nvme");
+
+        sysfs.link("devices/platform/coretemp.0/subsystem", "../../../bus/platform");
+        sysfs.hwmon(0, "devices/platform/coretemp.0", true, &[
+            ("name", "coretemp"),
+            ("temp1_label", "Package id 0"),
+            ("temp1_input", "45000"),
+            ("temp1_crit", "100000"),
+            ("temp1_crit_alarm", "0"),
+        ]);
+
+        sysfs.link("devices/platform/nct6775.656/subsystem", "../../../bus/platform");
+        sysfs.hwmon(1, "devices/platform/nct6775.656", true, &[
+            ("name", "nct6775"),
+            ("in0_input", "12100"),
+            ("fan1_input", "1200"),
+            ("power1_average", "15000000"),
+            ("energy1_input", "7200000000"),
+            ("intrusion0_alarm", "1"),
+        ]);
+
+        // NVMe controllers are not on a bus themselves, so the chip is named after the PCI
+        // device they belong to.
+        sysfs.link("devices/pci0000:00/0000:01:00.0/subsystem", "../../../bus/pci");
+        sysfs.link("devices/pci0000:00/0000:01:00.0/nvme/nvme0/subsystem", "../../../../../class/nvme");
+        sysfs.hwmon(2, "devices/pci0000:00/0000:01:00.0/nvme/nvme0", false, &[
+            ("name", "nvme"),
+            ("temp1_label", "Composite"),
+            ("temp1_input", "38850"),
+        ]);
+
+        // The hwmon chip of a thermal zone has no device.
+        sysfs.file("devices/virtual/hwmon/hwmon3/name", "acpitz");
+        sysfs.file("devices/virtual/hwmon/hwmon3/temp1_input", "50000");
+        sysfs.link("class/hwmon/hwmon3", "../../devices/virtual/hwmon/hwmon3");
+
+        // The zone of the hwmon chip above, and one without a chip of its own.
+        for (zone, zone_type, temperature) in [(0, "acpitz", "50000"), (1, "cpu-thermal", "51000")] {
+            let path = format!("devices/virtual/thermal/thermal_zone{}", zone);
+            sysfs.file(&format!("{}/type", path), zone_type);
+            sysfs.file(&format!("{}/temp", path), temperature);
+            sysfs.link(&format!("class/thermal/thermal_zone{}", zone), &format!("../../{}", path));
+        }
+        sysfs.file("devices/virtual/thermal/thermal_zone1/trip_point_0_type", "passive");
+        sysfs.file("devices/virtual/thermal/thermal_zone1/trip_point_0_temp", "80000");
+        sysfs.file("devices/virtual/thermal/thermal_zone1/trip_point_1_type", "critical");
+        sysfs.file("devices/virtual/thermal/thermal_zone1/trip_point_1_temp", "90000");
+
+        let sensors = SysfsSensors::new(&sysfs.root).read().unwrap();
+        assert_eq!(sensors.len(), 8);
+
+        let package = find(&sensors, "/sys/devices/platform/coretemp.0:temp1");
+        assert_eq!(package.chip, "coretemp-isa-0000");
+        assert_eq!(package.label, "Package id 0");
+        assert_eq!(package.kind.name(), "temperature");
+        assert_eq!(readings(package), ["crit=100", "crit_alarm=false", "input=45"]);
+
+        let voltage = find(&sensors, "/sys/devices/platform/nct6775.656:in0");
+        assert_eq!(voltage.chip, "nct6775-isa-0290");
+        assert_eq!(voltage.label, "in0");
+        assert_eq!(voltage.kind.name(), "voltage");
+        assert_eq!(readings(voltage), ["input=12.1"]);
+        assert_eq!(readings(find(&sensors, "/sys/devices/platform/nct6775.656:fan1")), ["input=1200"]);
+        assert_eq!(readings(find(&sensors, "/sys/devices/platform/nct6775.656:power1")), ["average=15"]);
+        assert_eq!(readings(find(&sensors, "/sys/devices/platform/nct6775.656:energy1")), ["input=7200"]);
+
+        let nvme = find(&sensors, "/sys/devices/pci0000:00/0000:01:00.0/nvme/nvme0:temp1");
+        assert_eq!(nvme.chip, "nvme-pci-0100");
+        assert_eq!(nvme.label, "Composite");
+        assert_eq!(readings(nvme), ["input=38.85"]);
+
+        let acpitz = find(&sensors, "acpitz-virtual-0:temp1");
+        assert_eq!(acpitz.chip, "acpitz-virtual-0");
+        assert_eq!(readings(acpitz), ["input=50"]);
+
+        let zone = find(&sensors, "thermal_zone1:temp1");
+        assert_eq!(zone.chip, "thermal_zone1");
+        assert_eq!(zone.label, "cpu-thermal");
+        assert_eq!(readings(zone), ["crit=90", "input=51"]);
+        assert!(sensors.iter().all(|sensor| sensor.chip != "thermal_zone0"));
+    }
+
+    #[test]
+    fn bus_addresses_match_libsensors() {
+        assert_eq!(bus_address("i2c", "1-002d").as_deref(), Some("i2c-1-2d"));
+        assert_eq!(bus_address("spi", "spi0.1").as_deref(), Some("spi-0-1"));
+        assert_eq!(bus_address("pci", "0000:c1:00.3").as_deref(), Some("pci-c103"));
+        assert_eq!(bus_address("platform", "it87.2608").as_deref(), Some("isa-0a30"));
+        assert_eq!(bus_address("platform", "cpu_thermal").as_deref(), Some("isa-0000"));
+        assert_eq!(bus_address("acpi", "ACPI0000:00").as_deref(), Some("acpi-0"));
+        assert_eq!(bus_address("hid", "0003:1B1C:1C05.0007").as_deref(), Some("hid-3-7"));
+        assert_eq!(bus_address("mdio_bus", "stmmac-0:01").as_deref(), Some("mdio-1"));
+        assert_eq!(bus_address("scsi", "0:0:1:0").as_deref(), Some("scsi-0-1"));
+        assert_eq!(bus_address("nvme", "nvme0"), None);
+        assert_eq!(bus_address("i2c", "i2c-1"), None);
+    }
+}
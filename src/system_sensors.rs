@@ -1,161 +1,269 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use futures_util::future::{BoxFuture, LocalBoxFuture};
 use serde_json::Value;
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use sysinfo::{System, Disks};
-use crate::config::Config;
+use crate::collector::{read_blocking, Collector};
+use crate::config::DriveConfig;
 use crate::home_assistant::{EntityRegistrationBuilder, HomeAssistant};
-use crate::lm_sensors_impl::SensorsImpl;
-use crate::nvidia_gpu::NvidiaGpuSensors;
-
-/// Register all system sensors with Home Assistant
-pub async fn register_system_sensors(home_assistant: &mut HomeAssistant, config: &Config) -> Result<()> {
-    // Register the various sensor topics and include the details about that sensor
-    home_assistant
-        .register_entity_with_builder(
-            EntityRegistrationBuilder::new("sensor", "available")
-                .icon("mdi:check-network-outline")
-        )
-        .await
-        .context("Failed to register availability topic.")?;
-    home_assistant
-        .register_entity_with_builder(
-            EntityRegistrationBuilder::new("sensor", "uptime")
-                .unit_of_measurement("days")
-                .icon("mdi:timer-sand")
-        )
-        .await
-        .context("Failed to register uptime topic.")?;
-    home_assistant
-        .register_entity_with_builder(
-            EntityRegistrationBuilder::new("sensor", "cpu")
-                .state_class("measurement")
-                .unit_of_measurement("%")
-                .icon("mdi:gauge")
-        )
-        .await
-        .context("Failed to register CPU usage topic.")?;
-    home_assistant
-        .register_entity_with_builder(
-            EntityRegistrationBuilder::new("sensor", "memory")
-                .state_class("measurement")
-                .unit_of_measurement("%")
-                .icon("mdi:gauge")
-        )
-        .await
-        .context("Failed to register memory usage topic.")?;
-    home_assistant
-        .register_entity_with_builder(
-            EntityRegistrationBuilder::new("sensor", "swap")
-                .state_class("measurement")
-                .unit_of_measurement("%")
-                .icon("mdi:gauge")
-        )
-        .await
-        .context("Failed to register swap usage topic.")?;
-    home_assistant
-        .register_entity_with_builder(
-            EntityRegistrationBuilder::new("sensor", "battery_level")
-                .device_class("battery")
-                .state_class("measurement")
-                .unit_of_measurement("%")
-                .icon("mdi:battery")
-        )
-        .await
-        .context("Failed to register battery level topic.")?;
-    home_assistant
-        .register_entity_with_builder(
-            EntityRegistrationBuilder::new("sensor", "battery_state")
-                .icon("mdi:battery")
-        )
-        .await
-        .context("Failed to register battery state topic.")?;
-
-    // Register the sensors for filesystems
-    for drive in &config.drives {
-        home_assistant
-            .register_entity_with_builder(
-                EntityRegistrationBuilder::new("sensor", &drive.name)
-                    .state_class("total")
-                    .unit_of_measurement("%")
-                    .icon("mdi:folder")
-            )
-            .await
-            .context("Failed to register a filesystem topic.")?;
+
+/// Collects uptime, CPU, memory and swap usage.
+pub struct SystemCollector {
+    system: Arc<Mutex<System>>,
+}
+
+impl SystemCollector {
+    pub fn new() -> Self {
+        let mut system = System::new_all();
+        system.refresh_all();
+
+        Self {
+            system: Arc::new(Mutex::new(system)),
+        }
     }
+}
+
+impl Collector for SystemCollector {
+    fn name(&self) -> &str {
+        "system"
+    }
+
+    fn register<'a>(&'a mut self, home_assistant: &'a mut HomeAssistant) -> LocalBoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            // Register the various sensor topics and include the details about that sensor
+            home_assistant
+                .register_entity_with_builder(
+                    EntityRegistrationBuilder::new("sensor", "uptime")
+                        .unit_of_measurement("days")
+                        .icon("mdi:timer-sand")
+                )
+                .await
+                .context("Failed to register uptime topic.")?;
+            home_assistant
+                .register_entity_with_builder(
+                    EntityRegistrationBuilder::new("sensor", "cpu")
+                        .state_class("measurement")
+                        .unit_of_measurement("%")
+                        .icon("mdi:gauge")
+                )
+                .await
+                .context("Failed to register CPU usage topic.")?;
+            home_assistant
+                .register_entity_with_builder(
+                    EntityRegistrationBuilder::new("sensor", "memory")
+                        .state_class("measurement")
+                        .unit_of_measurement("%")
+                        .icon("mdi:gauge")
+                )
+                .await
+                .context("Failed to register memory usage topic.")?;
+            home_assistant
+                .register_entity_with_builder(
+                    EntityRegistrationBuilder::new("sensor", "swap")
+                        .state_class("measurement")
+                        .unit_of_measurement("%")
+                        .icon("mdi:gauge")
+                )
+                .await
+                .context("Failed to register swap usage topic.")?;
+
+            Ok(())
+        })
+    }
+
+    fn collect<'a>(&'a mut self, stats: &'a mut HashMap<String, Value>) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let values = read_blocking(&self.system, |system| {
+                let mut stats = HashMap::new();
 
-    Ok(())
+                // Refresh system information
+                system.refresh_all();
+
+                // Collect uptime.
+                let uptime = System::uptime() as f32 / 60.0 / 60.0 / 24.0; // Convert from seconds to days.
+                stats.insert("uptime".to_string(), Value::from(uptime));
+
+                // Collect CPU usage.
+                let cpus = system.cpus();
+                let cpu_usage = (cpus.iter().map(|cpu| cpu.cpu_usage()).sum::<f32>()) / (cpus.len() as f32 * 100.0);
+                stats.insert("cpu".to_string(), Value::from(cpu_usage * 100.0));
+
+                // Collect memory usage.
+                let memory_percentile = (system.total_memory() - system.available_memory()) as f64 / system.total_memory() as f64;
+                stats.insert("memory".to_string(), Value::from(memory_percentile.clamp(0.0, 1.0) * 100.0));
+
+                // Collect swap usage.
+                let total_swap = system.used_swap() + system.free_swap();
+                let swap_percentile = if total_swap > 0 {
+                    system.used_swap() as f64 / total_swap as f64
+                } else {
+                    0.0
+                };
+                stats.insert("swap".to_string(), Value::from(swap_percentile.clamp(0.0, 1.0) * 100.0));
+
+                Ok(stats)
+            })
+            .await?;
+            stats.extend(values);
+
+            Ok(())
+        })
+    }
 }
 
-/// Collect system statistics and store them in the provided HashMap
-pub async fn collect_system_stats(
-    system: &mut System,
-    drive_list: &HashMap<PathBuf, String>,
-    manager: &battery::Manager,
-    sensors: &mut SensorsImpl,
-    gpu_sensors: &NvidiaGpuSensors,
-) -> Result<HashMap<String, Value>> {
-    // Refresh system information
-    system.refresh_all();
+/// Collects the state and charge level of the first battery.
+///
+/// The battery manager cannot be moved to another thread, so a new one is made for every read
+/// on the blocking thread pool.
+#[cfg(feature = "battery")]
+pub struct BatteryCollector;
 
-    let mut stats = HashMap::new();
+#[cfg(feature = "battery")]
+impl BatteryCollector {
+    /// Create the collector, or `None` if the machine has no battery, which it never will.
+    pub fn new() -> Result<Option<Self>> {
+        let manager = battery::Manager::new().context("Failed to initialize battery monitoring.")?;
+        let batteries = manager.batteries().context("Failed to read battery info.")?;
+        if batteries.count() == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(Self))
+    }
+}
 
-    // Collect uptime.
-    let uptime = System::uptime() as f32 / 60.0 / 60.0 / 24.0; // Convert from seconds to days.
-    stats.insert("uptime".to_string(), Value::from(uptime));
+/// Read the state and charge level of the first battery that can be read.
+#[cfg(feature = "battery")]
+fn read_battery() -> Result<HashMap<String, Value>> {
+    let manager = battery::Manager::new().context("Failed to initialize battery monitoring.")?;
+    let batteries = manager.batteries().context("Failed to read battery info.")?;
 
-    // Collect CPU usage.
-    let cpu_usage = (system.cpus().iter().map(|cpu| cpu.cpu_usage()).sum::<f32>()) / (system.cpus().len() as f32 * 100.0);
-    stats.insert("cpu".to_string(), Value::from(cpu_usage * 100.0));
+    // A battery that cannot be read is skipped in favor of the next one.
+    let battery = batteries.filter_map(|battery| {
+        battery
+            .map_err(|error| log::warn!("Failed to read a battery: {}", error))
+            .ok()
+    }).next();
+    let Some(battery) = battery else {
+        bail!("No battery found.");
+    };
 
-    // Collect memory usage.
-    let memory_percentile = (system.total_memory() - system.available_memory()) as f64 / system.total_memory() as f64;
-    stats.insert("memory".to_string(), Value::from(memory_percentile.clamp(0.0, 1.0) * 100.0));
+    use battery::State;
 
-    // Collect swap usage.
-    let total_swap = system.used_swap() + system.free_swap();
-    let swap_percentile = if total_swap > 0 {
-        system.used_swap() as f64 / total_swap as f64
-    } else {
-        0.0
+    let mut stats = HashMap::new();
+    let battery_state = match battery.state() {
+        State::Charging => "charging",
+        State::Discharging => "discharging",
+        State::Empty => "empty",
+        State::Full => "full",
+        _ => "unknown",
     };
-    stats.insert("swap".to_string(), Value::from(swap_percentile.clamp(0.0, 1.0) * 100.0));
-
-    // Collect filesystem usage.
-    let disks = Disks::new_with_refreshed_list();
-    for drive in &disks {
-        if let Some(drive_name) = drive_list.get(drive.mount_point()) {
-            let drive_percentile = (drive.total_space() - drive.available_space()) as f64 / drive.total_space() as f64;
-            stats.insert(drive_name.clone(), Value::from(drive_percentile.clamp(0.0, 1.0) * 100.0));
-        }
+    stats.insert("battery_state".to_string(), Value::from(battery_state));
+
+    let battery_full = battery.energy_full();
+    let battery_power = battery.energy();
+    let battery_level = battery_power / battery_full;
+
+    stats.insert("battery_level".to_string(), Value::from(battery_level.value));
+
+    Ok(stats)
+}
+
+#[cfg(feature = "battery")]
+impl Collector for BatteryCollector {
+    fn name(&self) -> &str {
+        "battery"
     }
 
-    // Collect battery information.
-    if let Some(battery) = manager.batteries().context("Failed to read battery info.")?.flatten().next() {
-        use battery::State;
+    fn register<'a>(&'a mut self, home_assistant: &'a mut HomeAssistant) -> LocalBoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            home_assistant
+                .register_entity_with_builder(
+                    EntityRegistrationBuilder::new("sensor", "battery_level")
+                        .device_class("battery")
+                        .state_class("measurement")
+                        .unit_of_measurement("%")
+                        .icon("mdi:battery")
+                )
+                .await
+                .context("Failed to register battery level topic.")?;
+            home_assistant
+                .register_entity_with_builder(
+                    EntityRegistrationBuilder::new("sensor", "battery_state")
+                        .icon("mdi:battery")
+                )
+                .await
+                .context("Failed to register battery state topic.")?;
 
-        let battery_state = match battery.state() {
-            State::Charging => "charging",
-            State::Discharging => "discharging",
-            State::Empty => "empty",
-            State::Full => "full",
-            _ => "unknown",
-        };
-        stats.insert("battery_state".to_string(), Value::from(battery_state));
+            Ok(())
+        })
+    }
 
-        let battery_full = battery.energy_full();
-        let battery_power = battery.energy();
-        let battery_level = battery_power / battery_full;
+    fn collect<'a>(&'a mut self, stats: &'a mut HashMap<String, Value>) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let values = tokio::task::spawn_blocking(read_battery)
+                .await
+                .context("Failed to run a blocking read.")??;
+            stats.extend(values);
 
-        stats.insert("battery_level".to_string(), Value::from(battery_level.value));
+            Ok(())
+        })
     }
+}
+
+/// Collects the usage of a single filesystem.
+pub struct DriveCollector {
+    name: String,
+    drive: DriveConfig,
+}
 
-    // Collect lm_sensors data.
-    sensors.collect_values(&mut stats).await?;
-    
-    if let Err(err) = gpu_sensors.collect_values(&mut stats).await {
-        log::warn!("Failed to collect GPU sensor data: {:#}", err);
+impl DriveCollector {
+    pub fn new(drive: DriveConfig) -> Self {
+        Self {
+            name: format!("drive_{}", drive.name),
+            drive,
+        }
     }
+}
 
-    Ok(stats)
+impl Collector for DriveCollector {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn register<'a>(&'a mut self, home_assistant: &'a mut HomeAssistant) -> LocalBoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            home_assistant
+                .register_entity_with_builder(
+                    EntityRegistrationBuilder::new("sensor", &self.drive.name)
+                        .state_class("total")
+                        .unit_of_measurement("%")
+                        .icon("mdi:folder")
+                )
+                .await
+                .context("Failed to register a filesystem topic.")?;
+
+            Ok(())
+        })
+    }
+
+    fn collect<'a>(&'a mut self, stats: &'a mut HashMap<String, Value>) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let path = self.drive.path.clone();
+            let drive_percentile = tokio::task::spawn_blocking(move || {
+                let disks = Disks::new_with_refreshed_list();
+                let Some(drive) = disks.iter().find(|drive| drive.mount_point() == path) else {
+                    bail!("Nothing is mounted at `{}`.", path.display());
+                };
+
+                Ok((drive.total_space() - drive.available_space()) as f64 / drive.total_space() as f64)
+            })
+            .await
+            .context("Failed to run a blocking read.")??;
+            stats.insert(self.drive.name.clone(), Value::from(drive_percentile.clamp(0.0, 1.0) * 100.0));
+
+            Ok(())
+        })
+    }
 }
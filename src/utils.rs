@@ -1,4 +1,52 @@
-/// Sanitize a sensor name by replacing spaces with dashes
-pub fn sanitize_sensor_name(name: String) -> String {
-    name.replace(" ", "-")
-}
\ No newline at end of file
+use anyhow::{Context, Result};
+use std::hash::{BuildHasher, Hasher, RandomState};
+use std::path::Path;
+use std::time::Duration;
+use tokio::fs;
+
+/// Turn a name into a valid entity ID.
+///
+/// Letters, digits, underscores and dashes are kept, and every other character is replaced
+/// by a dash. Names that were already valid once spaces are replaced by dashes keep the same ID.
+pub fn slugify(name: &str) -> String {
+    let slug: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '-' })
+        .collect();
+
+    if slug.chars().all(|c| c == '-') {
+        "unknown".to_string()
+    } else {
+        slug
+    }
+}
+
+/// Randomly shorten or lengthen a delay by up to `fraction` of its length.
+///
+/// Used to spread out reconnects and announcements of many devices sharing one broker.
+pub fn with_jitter(delay: Duration, fraction: f64) -> Duration {
+    // A freshly seeded hasher is a cheap source of randomness that needs no extra dependency.
+    let random = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+    delay.mul_f64(1.0 + fraction * (random * 2.0 - 1.0))
+}
+
+/// Replace the contents of a file, creating its directory if needed.
+///
+/// The contents are written to a temporary file first, so a crash never leaves a truncated
+/// file behind.
+pub async fn write_atomically(path: &Path, contents: &str) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .await
+            .with_context(|| format!("Failed to create `{}`.", parent.display()))?;
+    }
+
+    let mut temporary_path = path.as_os_str().to_owned();
+    temporary_path.push(".tmp");
+    fs::write(&temporary_path, contents)
+        .await
+        .with_context(|| format!("Failed to write `{}`.", path.display()))?;
+    fs::rename(&temporary_path, path)
+        .await
+        .with_context(|| format!("Failed to replace `{}`.", path.display()))
+}